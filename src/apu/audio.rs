@@ -0,0 +1,282 @@
+use super::channel::{NoiseChannel, SquareChannel, WaveChannel};
+use crate::{
+    mem::memory::MapsMemory,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+// Sound Control Registers
+const NR50_REGISTER: u16 = 0xFF24;
+const NR51_REGISTER: u16 = 0xFF25;
+const NR52_REGISTER: u16 = 0xFF26;
+
+const SQUARE1_REGISTERS: u16 = 0xFF10;
+const SQUARE2_REGISTERS: u16 = 0xFF15;
+const WAVE_REGISTERS: u16 = 0xFF1A;
+const NOISE_REGISTERS: u16 = 0xFF1F;
+
+const REGISTERS_START: u16 = 0xFF10;
+const REGISTERS_END: u16 = 0xFF2F;
+const WAVE_RAM_START: u16 = 0xFF30;
+const WAVE_RAM_END: u16 = 0xFF3F;
+
+pub const CLOCK_RATE: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// The frame sequencer runs at 512 Hz, clocked by the falling edge of bit 4 of DIV
+const FRAME_SEQUENCER_DIVIDER_BIT: u16 = 12;
+
+// Keep at most one second of audio if the frontend stops pulling samples
+const MAX_BUFFERED_SECONDS: usize = 1;
+
+pub(crate) struct AudioProcessingUnit {
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+
+    powered: bool,
+    master_volume: u8,
+    panning: u8,
+
+    frame_sequencer_step: u8,
+    last_divider_bit: bool,
+
+    sample_rate: u32,
+    sample_counter: u32,
+    samples: Vec<f32>,
+}
+
+impl AudioProcessingUnit {
+    pub fn new() -> AudioProcessingUnit {
+        AudioProcessingUnit {
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            powered: false,
+            master_volume: 0,
+            panning: 0,
+            frame_sequencer_step: 0,
+            last_divider_bit: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_counter: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Advances the APU by one T-cycle. `divider` is the timer's internal 16-bit divider.
+    pub fn step(&mut self, divider: u16) {
+        let divider_bit = (divider >> FRAME_SEQUENCER_DIVIDER_BIT) & 1 == 1;
+        if self.powered {
+            if self.last_divider_bit && !divider_bit {
+                self.clock_frame_sequencer();
+            }
+            self.square1.step();
+            self.square2.step();
+            self.wave.step();
+            self.noise.step();
+        }
+        self.last_divider_bit = divider_bit;
+
+        self.sample_counter += self.sample_rate;
+        if self.sample_counter >= CLOCK_RATE {
+            self.sample_counter -= CLOCK_RATE;
+            self.push_sample();
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_sequencer_step % 2 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn push_sample(&mut self) {
+        let (left, right) = self.mix();
+        self.samples.push(left);
+        self.samples.push(right);
+        let max_samples = self.sample_rate as usize * 2 * MAX_BUFFERED_SECONDS;
+        if self.samples.len() > max_samples {
+            let overflow = self.samples.len() - max_samples;
+            self.samples.drain(..overflow);
+        }
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0);
+        }
+        let outputs = [
+            Self::dac(self.square1.dac_enabled(), self.square1.output()),
+            Self::dac(self.square2.dac_enabled(), self.square2.output()),
+            Self::dac(self.wave.dac_enabled(), self.wave.output()),
+            Self::dac(self.noise.dac_enabled(), self.noise.output()),
+        ];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (channel, output) in outputs.iter().enumerate() {
+            if (self.panning >> (channel + 4)) & 1 == 1 {
+                left += output;
+            }
+            if (self.panning >> channel) & 1 == 1 {
+                right += output;
+            }
+        }
+        let left_volume = f32::from(((self.master_volume >> 4) & 0b111) + 1) / 8.0;
+        let right_volume = f32::from((self.master_volume & 0b111) + 1) / 8.0;
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    /// Converts a digital channel output (0-15) into the analog range -1.0 to 1.0.
+    fn dac(enabled: bool, output: u8) -> f32 {
+        if enabled {
+            f32::from(output) / 7.5 - 1.0
+        } else {
+            0.0
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        assert!(sample_rate > 0 && sample_rate <= CLOCK_RATE);
+        self.sample_rate = sample_rate;
+        self.sample_counter = 0;
+        self.samples.clear();
+    }
+
+    /// Drains the interleaved stereo samples produced since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn power_off(&mut self) {
+        self.square1 = SquareChannel::new(true);
+        self.square2 = SquareChannel::new(false);
+        self.wave.power_off();
+        self.noise = NoiseChannel::new();
+        self.master_volume = 0;
+        self.panning = 0;
+    }
+
+    fn read_status(&self) -> u8 {
+        ((self.powered as u8) << 7)
+            | 0b0111_0000
+            | ((self.noise.is_enabled() as u8) << 3)
+            | ((self.wave.is_enabled() as u8) << 2)
+            | ((self.square2.is_enabled() as u8) << 1)
+            | (self.square1.is_enabled() as u8)
+    }
+
+    fn write_status(&mut self, value: u8) {
+        let powered = (value >> 7) & 1 == 1;
+        if self.powered && !powered {
+            self.power_off();
+        } else if !self.powered && powered {
+            self.frame_sequencer_step = 0;
+        }
+        self.powered = powered;
+    }
+
+    /// While powered off only NR52 and, on the DMG, the length counters can be written.
+    fn write_length_while_off(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF11 => self.square1.write_length(value),
+            0xFF16 => self.square2.write_length(value),
+            0xFF1B => self.wave.write_length(value),
+            0xFF20 => self.noise.write_length(value),
+            _ => {}
+        }
+    }
+}
+
+impl MapsMemory for AudioProcessingUnit {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            SQUARE1_REGISTERS..=0xFF14 => {
+                Ok(self.square1.read_register(address - SQUARE1_REGISTERS))
+            }
+            SQUARE2_REGISTERS..=0xFF19 => {
+                Ok(self.square2.read_register(address - SQUARE2_REGISTERS))
+            }
+            WAVE_REGISTERS..=0xFF1E => Ok(self.wave.read_register(address - WAVE_REGISTERS)),
+            NOISE_REGISTERS..=0xFF23 => Ok(self.noise.read_register(address - NOISE_REGISTERS)),
+            NR50_REGISTER => Ok(self.master_volume),
+            NR51_REGISTER => Ok(self.panning),
+            NR52_REGISTER => Ok(self.read_status()),
+            0xFF27..=REGISTERS_END => Ok(0xFF),
+            WAVE_RAM_START..=WAVE_RAM_END => Ok(self.wave.read_wave_ram(address - WAVE_RAM_START)),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        if !self.is_in_range(address) {
+            return Err(());
+        }
+        if !self.powered && address != NR52_REGISTER && address < WAVE_RAM_START {
+            self.write_length_while_off(address, value);
+            return Ok(());
+        }
+        match address {
+            SQUARE1_REGISTERS..=0xFF14 => self
+                .square1
+                .write_register(address - SQUARE1_REGISTERS, value),
+            SQUARE2_REGISTERS..=0xFF19 => self
+                .square2
+                .write_register(address - SQUARE2_REGISTERS, value),
+            WAVE_REGISTERS..=0xFF1E => self.wave.write_register(address - WAVE_REGISTERS, value),
+            NOISE_REGISTERS..=0xFF23 => self.noise.write_register(address - NOISE_REGISTERS, value),
+            NR50_REGISTER => self.master_volume = value,
+            NR51_REGISTER => self.panning = value,
+            NR52_REGISTER => self.write_status(value),
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                self.wave.write_wave_ram(address - WAVE_RAM_START, value)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        (REGISTERS_START..=WAVE_RAM_END).contains(&address)
+    }
+}
+
+/// The sample rate and buffered samples belong to the frontend and are not part of the state.
+impl SaveState for AudioProcessingUnit {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.write_bool(self.powered);
+        writer.write_u8(self.master_volume);
+        writer.write_u8(self.panning);
+        writer.write_u8(self.frame_sequencer_step);
+        writer.write_bool(self.last_divider_bit);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.powered = reader.read_bool()?;
+        self.master_volume = reader.read_u8()?;
+        self.panning = reader.read_u8()?;
+        self.frame_sequencer_step = reader.read_u8()? % 8;
+        self.last_divider_bit = reader.read_bool()?;
+        Ok(())
+    }
+}
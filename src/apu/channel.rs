@@ -0,0 +1,659 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+const MAX_FREQUENCY: u16 = 2047;
+
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    fn load(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns false once the counter expires and the channel has to be disabled.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+}
+
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = (value >> 3) & 1 == 1;
+        self.period = value & 0b111;
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 0xF {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        0b1000_0000 | (self.period << 4) | ((self.negate as u8) << 3) | self.shift
+    }
+
+    fn write(&mut self, value: u8) {
+        self.period = (value >> 4) & 0b111;
+        self.negate = (value >> 3) & 1 == 1;
+        self.shift = value & 0b111;
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn calculate(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+pub(crate) struct SquareChannel {
+    enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl SquareChannel {
+    pub fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    pub fn read_register(&self, register: u16) -> u8 {
+        match register {
+            0 => self.sweep.as_ref().map(Sweep::read).unwrap_or(0xFF),
+            1 => (self.duty << 6) | 0b0011_1111,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            4 => ((self.length.enabled as u8) << 6) | 0b1011_1111,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(value);
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(u16::from(value & 0b0011_1111));
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | u16::from(value),
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (u16::from(value & 0b111) << 8);
+                self.length.enabled = (value >> 6) & 1 == 1;
+                if (value >> 7) & 1 == 1 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(u16::from(value & 0b0011_1111));
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = (2048 - self.frequency) * 4;
+        self.envelope.trigger();
+        let frequency = self.frequency;
+        let mut overflow = false;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow_frequency = frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            overflow = sweep.shift != 0 && sweep.calculate() > MAX_FREQUENCY;
+        }
+        if overflow {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 4;
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency = sweep.calculate();
+        if frequency > MAX_FREQUENCY {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow_frequency = frequency;
+            self.frequency = frequency;
+            if sweep.calculate() > MAX_FREQUENCY {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let high = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_position)) & 1;
+        high * self.envelope.volume
+    }
+}
+
+pub(crate) struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    length: LengthCounter,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [0; 16],
+        }
+    }
+
+    pub fn read_register(&self, register: u16) -> u8 {
+        match register {
+            0 => ((self.dac_enabled as u8) << 7) | 0b0111_1111,
+            1 => 0xFF,
+            2 => (self.volume_code << 5) | 0b1001_1111,
+            3 => 0xFF,
+            4 => ((self.length.enabled as u8) << 6) | 0b1011_1111,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = (value >> 7) & 1 == 1;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.write_length(value),
+            2 => self.volume_code = (value >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | u16::from(value),
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (u16::from(value & 0b111) << 8);
+                self.length.enabled = (value >> 6) & 1 == 1;
+                if (value >> 7) & 1 == 1 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(u16::from(value));
+    }
+
+    /// Powering the APU off clears every register but leaves wave RAM intact.
+    pub fn power_off(&mut self) {
+        *self = WaveChannel {
+            wave_ram: self.wave_ram,
+            ..WaveChannel::new()
+        };
+    }
+
+    pub fn read_wave_ram(&self, index: u16) -> u8 {
+        self.wave_ram[index as usize]
+    }
+
+    pub fn write_wave_ram(&mut self, index: u16, value: u8) {
+        self.wave_ram[index as usize] = value;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = (2048 - self.frequency) * 2;
+        self.position = 0;
+    }
+
+    pub fn step(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.wave_ram[(self.position / 2) as usize];
+        let sample = if self.position % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        match self.volume_code {
+            0 => 0,
+            1 => sample,
+            2 => sample >> 1,
+            3 => sample >> 2,
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub(crate) struct NoiseChannel {
+    enabled: bool,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub fn read_register(&self, register: u16) -> u8 {
+        match register {
+            0 | 1 => 0xFF,
+            2 => self.envelope.read(),
+            3 => (self.clock_shift << 4) | ((self.width_mode as u8) << 3) | self.divisor_code,
+            4 => ((self.length.enabled as u8) << 6) | 0b1011_1111,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {}
+            1 => self.write_length(value),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_mode = (value >> 3) & 1 == 1;
+                self.divisor_code = value & 0b111;
+            }
+            4 => {
+                self.length.enabled = (value >> 6) & 1 == 1;
+                if (value >> 7) & 1 == 1 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_length(&mut self, value: u8) {
+        self.length.load(u16::from(value & 0b0011_1111));
+    }
+
+    fn period(&self) -> u32 {
+        u32::from(NOISE_DIVISORS[self.divisor_code as usize]) << self.clock_shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn step(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            let feedback = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (!self.lfsr & 1) as u8 * self.envelope.volume
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.counter = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.read());
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.write(reader.read_u8()?);
+        self.volume = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.read());
+        writer.write_u8(self.timer);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.write(reader.read_u8()?);
+        self.timer = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.shadow_frequency = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.duty_position);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()? & 0b11;
+        self.duty_position = reader.read_u8()? % 8;
+        self.frequency = reader.read_u16()? & MAX_FREQUENCY;
+        self.timer = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.position);
+        self.length.save_state(writer);
+        writer.write_bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.volume_code = reader.read_u8()? & 0b11;
+        self.frequency = reader.read_u16()? & MAX_FREQUENCY;
+        self.timer = reader.read_u16()?;
+        self.position = reader.read_u8()? % 32;
+        self.length.load_state(reader)?;
+        reader.read_bytes_into(&mut self.wave_ram)
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.read_register(3));
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        let polynomial = reader.read_u8()?;
+        self.clock_shift = polynomial >> 4;
+        self.width_mode = (polynomial >> 3) & 1 == 1;
+        self.divisor_code = polynomial & 0b111;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)
+    }
+}
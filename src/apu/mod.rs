@@ -0,0 +1,3 @@
+pub mod audio;
+pub mod channel;
+pub mod wav;
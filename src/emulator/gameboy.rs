@@ -1,9 +1,13 @@
 use crate::debug::vram_fetcher::VRAMFetcher;
 use crate::debug::vram_fetcher::VramDebugger;
 use crate::{
-    gpu::screen::ScreenFetcher,
+    apu::audio::{CLOCK_RATE, DEFAULT_SAMPLE_RATE},
+    emulator::model::Model,
+    gpu::{compatibility::CompatibilityPalette, palette::Palette, screen::ScreenFetcher},
+    input::joypad::Button,
     mem::cartridge::Cartridge,
     processor::{cpu::Cpu, interrupt_controller::InterruptController},
+    state::StateError,
 };
 use image::{ImageBuffer, Rgba};
 use std::{cell::RefCell, rc::Rc};
@@ -18,18 +22,40 @@ pub struct Gameboy {
     cpu: Option<Cpu>,
     lcd_fetcher: Rc<RefCell<ScreenFetcher>>,
     boot_rom: Option<Vec<u8>>,
+    model: Model,
+    sample_rate: u32,
+    palette: Palette,
+    color_correction: bool,
+    compatibility_palette: Option<CompatibilityPalette>,
 }
 
 impl Gameboy {
-    pub fn new(boot_rom: Option<Vec<u8>>) -> Self {
+    /// The boot ROM has to belong to `model`; without one the machine starts in the state the
+    /// boot ROM would leave it in.
+    pub fn new(boot_rom: Option<Vec<u8>>, model: Model) -> Self {
         let lcd_fetcher = Rc::new(RefCell::new(ScreenFetcher::new()));
         Gameboy {
             cpu: None,
             lcd_fetcher,
             boot_rom,
+            model,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            palette: Palette::default(),
+            color_correction: false,
+            compatibility_palette: None,
         }
     }
 
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if let Some(cpu) = &mut self.cpu {
+            cpu.set_button(button, pressed);
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
     pub fn game_title(&self) -> &str {
         if let Some(cpu) = &self.cpu {
             cpu.game_title()
@@ -38,6 +64,112 @@ impl Gameboy {
         }
     }
 
+    /// The battery-backed RAM of the loaded cartridge, ready to be written to a `.sav` file.
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.cpu.as_ref().and_then(|cpu| cpu.save_ram())
+    }
+
+    pub fn load_ram(&mut self, data: &[u8]) {
+        if let Some(cpu) = &mut self.cpu {
+            cpu.load_ram(data);
+        }
+    }
+
+    /// A snapshot of the whole machine that `load_state` can return to. Empty if no cartridge is
+    /// loaded.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu
+            .as_ref()
+            .map(|cpu| cpu.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Restores a state written by `save_state` for the same ROM. On error the running game is
+    /// left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        match &mut self.cpu {
+            Some(cpu) => cpu.restore(data),
+            None => Err(StateError::NoCartridge),
+        }
+    }
+
+    /// Whether the rumble motor of the loaded cartridge is currently turned on.
+    pub fn rumble(&self) -> bool {
+        if let Some(cpu) = &self.cpu {
+            cpu.rumble()
+        } else {
+            false
+        }
+    }
+
+    /// Rates outside 1 Hz to the CPU clock rate are clamped to that range.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let sample_rate = sample_rate.clamp(1, CLOCK_RATE);
+        self.sample_rate = sample_rate;
+        if let Some(cpu) = &mut self.cpu {
+            cpu.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Interleaved stereo samples in the range -1.0 to 1.0 generated since the last call.
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        if let Some(cpu) = &mut self.cpu {
+            cpu.take_audio_samples()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Interleaved stereo 16-bit samples generated since the last call.
+    pub fn audio_samples_i16(&mut self) -> Vec<i16> {
+        self.audio_samples()
+            .iter()
+            .map(|sample| (sample * f32::from(i16::MAX)) as i16)
+            .collect()
+    }
+
+    /// Selects the colours the four DMG shades are displayed with, starting with the next frame.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        if let Some(cpu) = &mut self.cpu {
+            cpu.set_palette(palette);
+        }
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    /// Maps CGB colours to what the original LCD shows instead of full-intensity RGB.
+    pub fn set_color_correction(&mut self, color_correction: bool) {
+        self.color_correction = color_correction;
+        if let Some(cpu) = &mut self.cpu {
+            cpu.set_color_correction(color_correction);
+        }
+    }
+
+    pub fn color_correction(&self) -> bool {
+        self.color_correction
+    }
+
+    /// Picks the colours of a DMG game on the CGB like holding a button combination during the
+    /// boot logo does. Without one the palette is chosen from the cartridge title and licensee.
+    /// Has no effect on the DMG, for CGB games or when a boot ROM is used.
+    pub fn set_compatibility_palette(&mut self, palette: Option<CompatibilityPalette>) {
+        self.compatibility_palette = palette;
+        if let Some(cpu) = &mut self.cpu {
+            cpu.set_compatibility_palette(palette);
+        }
+    }
+
+    pub fn compatibility_palette(&self) -> Option<CompatibilityPalette> {
+        self.compatibility_palette
+    }
+
     pub fn screen(&self) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
         RefCell::borrow(&self.lcd_fetcher).image().clone()
     }
@@ -64,7 +196,12 @@ impl Emulator for Gameboy {
             cartridge,
             self.lcd_fetcher.clone(),
             self.boot_rom.clone(),
+            self.model,
         ));
+        self.set_sample_rate(self.sample_rate);
+        self.set_palette(self.palette);
+        self.set_color_correction(self.color_correction);
+        self.set_compatibility_palette(self.compatibility_palette);
     }
 }
 
@@ -0,0 +1,10 @@
+/// The hardware that is emulated.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Model {
+    /// The original Game Boy
+    #[default]
+    Dmg,
+    /// The Game Boy Color. Games that support it run in CGB mode, all others in compatibility
+    /// mode.
+    Cgb,
+}
@@ -0,0 +1,77 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const PALETTE_RAM_SIZE: usize = 64;
+const AUTO_INCREMENT_BIT: u8 = 0b1000_0000;
+const INDEX_MASK: u8 = 0b0011_1111;
+
+/// The 64 bytes of CGB palette RAM behind BCPS/BCPD or OCPS/OCPD: eight palettes of four
+/// little-endian BGR555 colours.
+pub(crate) struct ColorPalettes {
+    data: [u8; PALETTE_RAM_SIZE],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl ColorPalettes {
+    pub fn new() -> ColorPalettes {
+        ColorPalettes {
+            data: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_specification(&self) -> u8 {
+        let auto_increment = if self.auto_increment {
+            AUTO_INCREMENT_BIT
+        } else {
+            0
+        };
+        auto_increment | 0b0100_0000 | self.index
+    }
+
+    pub fn write_specification(&mut self, value: u8) {
+        self.auto_increment = value & AUTO_INCREMENT_BIT != 0;
+        self.index = value & INDEX_MASK;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[usize::from(self.index)]
+    }
+
+    /// Only writes advance the index, reads leave it where it is.
+    pub fn write_data(&mut self, value: u8) {
+        self.data[usize::from(self.index)] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & INDEX_MASK;
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: u8, colors: [u16; 4]) {
+        let start = usize::from(palette & 0b111) * 8;
+        for (index, color) in colors.iter().enumerate() {
+            let bytes = color.to_le_bytes();
+            self.data[start + index * 2..start + index * 2 + 2].copy_from_slice(&bytes);
+        }
+    }
+
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let index = usize::from(palette & 0b111) * 8 + usize::from(color & 0b11) * 2;
+        u16::from_le_bytes([self.data[index], self.data[index + 1]])
+    }
+}
+
+impl SaveState for ColorPalettes {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+        writer.write_u8(self.index);
+        writer.write_bool(self.auto_increment);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.data)?;
+        self.index = reader.read_u8()? & INDEX_MASK;
+        self.auto_increment = reader.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use crate::mem::cartridge::Cartridge;
+
+const NINTENDO_LICENSEE: u8 = 0x01;
+const NINTENDO_NEW_LICENSEE: &str = "01";
+
+/// Palettes of four BGR555 colours, the combinations below point into this table.
+#[rustfmt::skip]
+const PALETTES: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+/// The OBJ0, OBJ1 and BG palette of each combination as an offset into `PALETTES`. A few of
+/// them start in the middle of a palette, just like in the boot ROM.
+const COMBINATIONS: [[usize; 3]; 51] = [
+    [4 * 4, 4 * 4, 29 * 4],
+    [18 * 4, 18 * 4, 18 * 4],
+    [20 * 4, 20 * 4, 20 * 4],
+    [24 * 4, 24 * 4, 24 * 4],
+    [9 * 4, 9 * 4, 9 * 4],
+    [0, 0, 0],
+    [27 * 4, 27 * 4, 27 * 4],
+    [5 * 4, 5 * 4, 5 * 4],
+    [12 * 4, 12 * 4, 12 * 4],
+    [26 * 4, 26 * 4, 26 * 4],
+    [16 * 4, 8 * 4, 8 * 4],
+    [4 * 4, 28 * 4, 28 * 4],
+    [4 * 4, 2 * 4, 2 * 4],
+    [3 * 4, 4 * 4, 4 * 4],
+    [4 * 4, 29 * 4, 29 * 4],
+    [28 * 4, 4 * 4, 28 * 4],
+    [2 * 4, 17 * 4, 2 * 4],
+    [16 * 4, 16 * 4, 8 * 4],
+    [4 * 4, 4 * 4, 7 * 4],
+    [4 * 4, 4 * 4, 18 * 4],
+    [4 * 4, 4 * 4, 20 * 4],
+    [19 * 4, 19 * 4, 9 * 4],
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    [17 * 4, 17 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 3 * 4],
+    [28 * 4, 28 * 4, 0],
+    [3 * 4, 3 * 4, 0],
+    [0, 0, 4],
+    [18 * 4, 22 * 4, 18 * 4],
+    [20 * 4, 22 * 4, 20 * 4],
+    [24 * 4, 22 * 4, 24 * 4],
+    [16 * 4, 22 * 4, 8 * 4],
+    [17 * 4, 4 * 4, 13 * 4],
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    [19 * 4, 22 * 4, 9 * 4],
+    [16 * 4, 28 * 4, 10 * 4],
+    [4 * 4, 23 * 4, 28 * 4],
+    [17 * 4, 22 * 4, 2 * 4],
+    [4 * 4, 0, 2 * 4],
+    [4 * 4, 28 * 4, 3 * 4],
+    [28 * 4, 3 * 4, 0],
+    [3 * 4, 28 * 4, 4 * 4],
+    [21 * 4, 28 * 4, 4 * 4],
+    [3 * 4, 28 * 4, 0],
+    [25 * 4, 3 * 4, 28 * 4],
+    [0, 28 * 4, 8 * 4],
+    [4 * 4, 3 * 4, 28 * 4],
+    [28 * 4, 3 * 4, 6 * 4],
+    [4 * 4, 28 * 4, 29 * 4],
+];
+
+/// Sums of the title bytes of the Nintendo games the boot ROM knows. Index 0 stands for every
+/// other game.
+#[rustfmt::skip]
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E,
+    0x70, 0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15,
+    0xFF, 0x97, 0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0,
+    0x8B, 0xF0, 0xCE, 0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD,
+    0x5D, 0x6D, 0x67, 0x3F, 0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66,
+    0x6A, 0xBF, 0x0D, 0xF4,
+];
+
+// Checksums from this index on are shared by several games and need the fourth title letter
+const AMBIGUOUS_CHECKSUMS: usize = 65;
+
+/// The fourth title letter for each shared checksum, in rows of 14.
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// The combination for every checksum, followed by one per entry in `FOURTH_LETTERS`.
+#[rustfmt::skip]
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5,
+    29, 5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5,
+    42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11,
+    39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+/// The palettes the CGB boot ROM lets the player pick for DMG games by holding a direction,
+/// optionally together with A or B, while the logo is shown.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompatibilityPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    /// The palette of games the boot ROM doesn't recognize.
+    Right,
+    RightA,
+    RightB,
+}
+
+impl CompatibilityPalette {
+    fn combination(self) -> usize {
+        match self {
+            CompatibilityPalette::Up => 5,
+            CompatibilityPalette::UpA => 43,
+            CompatibilityPalette::UpB => 28,
+            CompatibilityPalette::Left => 48,
+            CompatibilityPalette::LeftA => 40,
+            CompatibilityPalette::LeftB => 7,
+            CompatibilityPalette::Down => 8,
+            CompatibilityPalette::DownA => 3,
+            CompatibilityPalette::DownB => 49,
+            CompatibilityPalette::Right => 0,
+            CompatibilityPalette::RightA => 1,
+            CompatibilityPalette::RightB => 6,
+        }
+    }
+
+    pub(crate) fn colors(self) -> CompatibilityColors {
+        CompatibilityColors::new(self.combination())
+    }
+}
+
+/// The colours the DMG shades of BGP, OBP0 and OBP1 are shown with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct CompatibilityColors {
+    pub background: [u16; 4],
+    pub objects: [[u16; 4]; 2],
+}
+
+impl CompatibilityColors {
+    fn new(combination: usize) -> CompatibilityColors {
+        let palette = |offset: usize| {
+            let mut colors = [0; 4];
+            colors.copy_from_slice(&PALETTES[offset..offset + 4]);
+            colors
+        };
+        let [object0, object1, background] = COMBINATIONS[combination];
+        CompatibilityColors {
+            background: palette(background),
+            objects: [palette(object0), palette(object1)],
+        }
+    }
+
+    /// Picks the colours like the boot ROM: only Nintendo games are looked up by the checksum
+    /// of their title, every other game gets the default palette.
+    pub fn for_cartridge(cartridge: &Cartridge) -> CompatibilityColors {
+        let info = cartridge.info();
+        // The new licensee code is only set if the old one says to use it
+        let nintendo = info.old_licensee_code == NINTENDO_LICENSEE
+            || info.new_licensee_code.as_deref() == Some(NINTENDO_NEW_LICENSEE);
+        let index = if nintendo {
+            Self::title_index(cartridge.title_bytes())
+        } else {
+            0
+        };
+        CompatibilityColors::new(usize::from(CHECKSUM_COMBINATIONS[index]))
+    }
+
+    fn title_index(title: &[u8]) -> usize {
+        let checksum = title.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        match TITLE_CHECKSUMS.iter().position(|&entry| entry == checksum) {
+            Some(index) if index < AMBIGUOUS_CHECKSUMS => index,
+            Some(index) => {
+                let fourth_letter = title.get(3).cloned().unwrap_or(0);
+                (index - AMBIGUOUS_CHECKSUMS..FOURTH_LETTERS.len())
+                    .step_by(TITLE_CHECKSUMS.len() - AMBIGUOUS_CHECKSUMS)
+                    .find(|&letter| FOURTH_LETTERS[letter] == fourth_letter)
+                    .map_or(0, |letter| AMBIGUOUS_CHECKSUMS + letter)
+            }
+            None => 0,
+        }
+    }
+}
@@ -1,2 +1,5 @@
+pub mod color_palette;
+pub mod compatibility;
+pub mod palette;
 pub mod ppu;
 pub mod screen;
@@ -0,0 +1,33 @@
+use image::Rgba;
+
+/// The colours used to display the four DMG shades, from lightest to darkest.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Palette {
+    /// The yellow-green tint of the original DMG LCD
+    ClassicGreen,
+    /// The neutral greys of the Game Boy Pocket
+    #[default]
+    PocketGrey,
+    /// User-defined RGB colours, lightest shade first
+    Custom([[u8; 3]; 4]),
+}
+
+impl Palette {
+    pub fn colors(&self) -> [[u8; 3]; 4] {
+        match self {
+            Palette::ClassicGreen => [
+                [0x9B, 0xBC, 0x0F],
+                [0x8B, 0xAC, 0x0F],
+                [0x30, 0x62, 0x30],
+                [0x0F, 0x38, 0x0F],
+            ],
+            Palette::PocketGrey => [[255, 255, 255], [180, 180, 180], [90, 90, 90], [0, 0, 0]],
+            Palette::Custom(colors) => *colors,
+        }
+    }
+
+    pub(crate) fn color(&self, shade: u8) -> Rgba<u8> {
+        let [r, g, b] = self.colors()[shade as usize];
+        Rgba([r, g, b, 255])
+    }
+}
@@ -1,18 +1,53 @@
-use super::screen::{Screen, ScreenFetcher};
+use super::{
+    color_palette::ColorPalettes,
+    compatibility::CompatibilityColors,
+    palette::Palette,
+    screen::{Screen, ScreenFetcher},
+};
 use crate::{
     mem::memory::{MapsMemory, Memory},
-    processor::interrupt_controller::InterruptController,
+    processor::{
+        dma::VramDma,
+        interrupt_controller::{Interrupt, InterruptController},
+    },
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
-use std::cmp::Ordering;
 use std::{cell::RefCell, rc::Rc};
 
 // LCD Control Register
 const LCDC_REGISTER: u16 = 0xFF40;
 
+// LCD Status Register
+const STAT_REGISTER: u16 = 0xFF41;
+
 // LCD Position and Scrolling
 const SCY_REGISTER: u16 = 0xFF42;
 const SCX_REGISTER: u16 = 0xFF43;
 const LY_REGISTER: u16 = 0xFF44;
+const LYC_REGISTER: u16 = 0xFF45;
+const WY_REGISTER: u16 = 0xFF4A;
+const WX_REGISTER: u16 = 0xFF4B;
+
+// CGB VRAM Bank Select
+const VBK_REGISTER: u16 = 0xFF4F;
+
+// LCD Monochrome Palettes
+const BGP_REGISTER: u16 = 0xFF47;
+const OBP0_REGISTER: u16 = 0xFF48;
+const OBP1_REGISTER: u16 = 0xFF49;
+
+// LCD Color Palettes (CGB only)
+const BCPS_REGISTER: u16 = 0xFF68;
+const BCPD_REGISTER: u16 = 0xFF69;
+const OCPS_REGISTER: u16 = 0xFF6A;
+const OCPD_REGISTER: u16 = 0xFF6B;
+
+// BG map attributes in VRAM bank 1
+const ATTRIBUTE_PALETTE: u8 = 0b0000_0111;
+const ATTRIBUTE_TILE_BANK: u8 = 0b0000_1000;
+const ATTRIBUTE_X_FLIP: u8 = 0b0010_0000;
+const ATTRIBUTE_Y_FLIP: u8 = 0b0100_0000;
+const ATTRIBUTE_PRIORITY: u8 = 0b1000_0000;
 
 const OAM_SEARCH_TICKS: usize = 20 * 4;
 const PIXEL_TRANSFER_AND_HBLANK_TICKS: usize = 94 * 4;
@@ -20,12 +55,19 @@ const PIXEL_TRANSFER_AND_HBLANK_TICKS: usize = 94 * 4;
 const LINES_TO_DRAW: usize = 144;
 const LINES_VBLANK: usize = 10;
 
-const TICKS_PER_LINE: usize = OAM_SEARCH_TICKS + PIXEL_TRANSFER_AND_HBLANK_TICKS;
+pub const TICKS_PER_LINE: usize = OAM_SEARCH_TICKS + PIXEL_TRANSFER_AND_HBLANK_TICKS;
 const LINES_PER_CYCLE: usize = LINES_TO_DRAW + LINES_VBLANK;
 pub const TICKS_PER_CYCLE: usize = LINES_PER_CYCLE * TICKS_PER_LINE;
 
-const TILES_IN_LINES: u16 = 0x14;
+const PIXELS_IN_LINE: usize = 160;
+const TILES_IN_MAP_LINE: u16 = 0x20;
+
+const OAM_ENTRIES: u16 = 40;
+const MAX_SPRITES_PER_LINE: usize = 10;
+// Reading a sprite's tile number and both data bytes stalls the transfer for three fetcher steps
+const SPRITE_FETCH_TICKS: u8 = 6;
 
+#[derive(Copy, Clone, PartialEq)]
 enum PPUMode {
     HBlank = 0,
     VBlank = 1,
@@ -33,13 +75,43 @@ enum PPUMode {
     Transfer = 3,
 }
 
+impl PPUMode {
+    fn from_bits(bits: u8) -> Option<PPUMode> {
+        match bits {
+            0 => Some(PPUMode::HBlank),
+            1 => Some(PPUMode::VBlank),
+            2 => Some(PPUMode::OamSearch),
+            3 => Some(PPUMode::Transfer),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) struct PixelProcessingUnit {
     memory: Memory,
+    memory_bank1: Memory,
+    vram_bank: u8,
+    cgb_mode: bool,
+    // Set when a DMG game on the CGB shows its shades through the colour palettes
+    compatibility_palettes: bool,
     oam: Memory,
+    background_palettes: ColorPalettes,
+    object_palettes: ColorPalettes,
 
     lcd: Screen,
     pixel_fifo: PixelFifo,
     fetcher: Fetcher,
+    // The sprites found by the OAM search that the transfer hasn't reached yet, left to right
+    line_sprites: Vec<Sprite>,
+    sprite_fetch_ticks: u8,
+
+    window_y_reached: bool,
+    window_line: u8,
+    window_in_line: bool,
+
+    stat_interrupt_select: u8,
+    lyc: u8,
+    stat_line: bool,
 
     current_tick: usize,
     current_pixel: u8,
@@ -48,22 +120,37 @@ pub(crate) struct PixelProcessingUnit {
 }
 
 impl PixelProcessingUnit {
-    pub fn new(lcd_fetcher: Rc<RefCell<ScreenFetcher>>) -> PixelProcessingUnit {
+    pub fn new(lcd_fetcher: Rc<RefCell<ScreenFetcher>>, cgb_mode: bool) -> PixelProcessingUnit {
         let memory = Memory::new_read_write(&[0u8; 0], 0x8000, 0x9FFF);
+        let memory_bank1 = Memory::new_read_write(&[0u8; 0], 0x8000, 0x9FFF);
         let oam = Memory::new_read_write(&[0u8; 0], 0xFE00, 0xFE9F);
 
         let lcd = Screen::new(lcd_fetcher);
         let pixel_fifo = PixelFifo::new();
-        let fetcher = Fetcher::new();
+        let fetcher = Fetcher::new(cgb_mode);
         let current_tick = 0;
         let current_pixel = 0;
         let current_line = 0;
         PixelProcessingUnit {
             memory,
+            memory_bank1,
+            vram_bank: 0,
+            cgb_mode,
+            compatibility_palettes: false,
             oam,
+            background_palettes: ColorPalettes::new(),
+            object_palettes: ColorPalettes::new(),
             lcd,
             pixel_fifo,
             fetcher,
+            line_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            sprite_fetch_ticks: 0,
+            window_y_reached: false,
+            window_line: 0,
+            window_in_line: false,
+            stat_interrupt_select: 0,
+            lyc: 0,
+            stat_line: false,
             current_tick,
             current_pixel,
             mode: PPUMode::OamSearch,
@@ -71,121 +158,486 @@ impl PixelProcessingUnit {
         }
     }
 
-    pub fn step(&mut self, io_registers: &mut Memory, _interrupt: &mut InterruptController) {
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.lcd.set_palette(palette);
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: bool) {
+        self.lcd.set_color_correction(color_correction);
+    }
+
+    pub fn has_compatibility_palettes(&self) -> bool {
+        self.compatibility_palettes
+    }
+
+    /// Loads the colours the CGB boot ROM gives DMG games into background palette 0 and object
+    /// palettes 0 and 1.
+    pub fn set_compatibility_palettes(&mut self, colors: CompatibilityColors) {
+        self.background_palettes.set_palette(0, colors.background);
+        self.object_palettes.set_palette(0, colors.objects[0]);
+        self.object_palettes.set_palette(1, colors.objects[1]);
+        self.compatibility_palettes = true;
+    }
+
+    /// The VRAM bank the CPU sees, selected by VBK in CGB mode.
+    fn selected_vram(&self) -> &Memory {
+        if self.vram_bank == 1 {
+            &self.memory_bank1
+        } else {
+            &self.memory
+        }
+    }
+
+    fn selected_vram_mut(&mut self) -> &mut Memory {
+        if self.vram_bank == 1 {
+            &mut self.memory_bank1
+        } else {
+            &mut self.memory
+        }
+    }
+
+    pub fn step(
+        &mut self,
+        io_registers: &mut Memory,
+        interrupt: &mut InterruptController,
+        hdma: &mut VramDma,
+    ) {
         match self.mode {
-            PPUMode::HBlank => self.h_blank(io_registers),
-            PPUMode::VBlank => self.v_blank(io_registers),
+            PPUMode::HBlank => self.h_blank(interrupt, hdma),
+            PPUMode::VBlank => self.v_blank(),
             PPUMode::OamSearch => self.oam_search(io_registers),
             PPUMode::Transfer => self.pixel_transfer(io_registers),
         }
+        self.update_stat_line(interrupt);
 
-        if self.current_tick <= TICKS_PER_CYCLE {
-            self.current_tick += 1;
-        } else {
+        self.current_tick += 1;
+        if self.current_tick == TICKS_PER_CYCLE {
             self.current_tick = 0;
             self.pixel_fifo.reset();
             self.fetcher.reset();
         }
     }
 
-    pub fn v_blank(&mut self, io_registers: &mut Memory) {
+    /// The four STAT sources are ORed into a single line and only its rising edge requests an
+    /// interrupt, so a source becoming active while another one is still active is blocked.
+    fn update_stat_line(&mut self, interrupt: &mut InterruptController) {
+        let select = self.stat_interrupt_select;
+        let stat_line = ((select >> 6) & 1 == 1 && self.current_line == self.lyc)
+            || ((select >> 5) & 1 == 1 && self.mode == PPUMode::OamSearch)
+            || ((select >> 4) & 1 == 1 && self.mode == PPUMode::VBlank)
+            || ((select >> 3) & 1 == 1 && self.mode == PPUMode::HBlank);
+        if stat_line && !self.stat_line {
+            interrupt.request(Interrupt::LcdStat);
+        }
+        self.stat_line = stat_line;
+    }
+
+    fn read_stat(&self) -> u8 {
+        let coincidence = (self.current_line == self.lyc) as u8;
+        0b1000_0000 | self.stat_interrupt_select | (coincidence << 2) | self.mode as u8
+    }
+
+    pub fn v_blank(&mut self) {
         if (self.current_tick + 1) % TICKS_PER_LINE == 0 {
             if (self.current_tick + 1) % TICKS_PER_CYCLE == 0 {
                 self.mode = PPUMode::OamSearch;
                 self.current_line = 0;
+                self.window_y_reached = false;
+                self.window_line = 0;
             } else {
                 self.current_line += 1;
             }
 
             self.current_pixel = 0;
-            io_registers.write(LY_REGISTER, self.current_line).unwrap();
         }
     }
 
-    pub fn h_blank(&mut self, io_registers: &mut Memory) {
+    pub fn h_blank(&mut self, interrupt: &mut InterruptController, hdma: &mut VramDma) {
+        // The transfer leaves the pixel counter at the end of the line until HBlank starts
+        if self.current_pixel as usize == PIXELS_IN_LINE {
+            self.current_pixel = 0;
+            hdma.h_blank();
+        }
         if (self.current_tick + 1) % TICKS_PER_LINE == 0 {
+            self.current_line += 1;
             if self.current_line as usize == LINES_TO_DRAW {
                 self.mode = PPUMode::VBlank;
-                self.current_line = 0;
                 self.lcd.display();
+                interrupt.request(Interrupt::VBlank);
             } else {
                 self.mode = PPUMode::OamSearch;
-                self.current_line += 1;
             }
 
             self.current_pixel = 0;
-            io_registers.write(LY_REGISTER, self.current_line).unwrap();
         }
     }
 
-    pub fn oam_search(&mut self, _io_registers: &mut Memory) {
+    pub fn oam_search(&mut self, io_registers: &mut Memory) {
         if (((self.current_tick + 1) % TICKS_PER_LINE) % OAM_SEARCH_TICKS) == 0 {
+            self.select_line_sprites(io_registers);
+            self.start_line(io_registers);
             self.mode = PPUMode::Transfer;
         }
-        // Todo!
-        // println!("oam search @ tick: {}", self.current_tick);
+    }
+
+    fn start_line(&mut self, io_registers: &Memory) {
+        if io_registers.read(WY_REGISTER).unwrap() == self.current_line {
+            self.window_y_reached = true;
+        }
+        self.window_in_line = false;
+        self.pixel_fifo.reset();
+        // Fine scrolling drops the first SCX % 8 pixels, which also lengthens mode 3
+        let scx = io_registers.read(SCX_REGISTER).unwrap();
+        self.pixel_fifo.discard(scx % 8);
+        self.fetcher.start_line(self.current_line);
+    }
+
+    /// Switches the fetcher over to the window once the current pixel reaches WX - 7. For
+    /// WX < 7 the window starts at the left edge with its first 7 - WX pixels cut off.
+    fn check_window(&mut self, io_registers: &Memory) {
+        if self.fetcher.is_fetching_window() || !self.window_y_reached {
+            return;
+        }
+        let lcd_control_register = io_registers.read(LCDC_REGISTER).unwrap();
+        if (lcd_control_register >> 5) & 1 == 0 {
+            return;
+        }
+        let wx = io_registers.read(WX_REGISTER).unwrap();
+        if u16::from(self.current_pixel) + 7 < u16::from(wx) {
+            return;
+        }
+        self.pixel_fifo.reset_background();
+        if wx < 7 {
+            self.pixel_fifo.discard(7 - wx);
+        }
+        self.fetcher.start_window(self.window_line);
+        self.window_in_line = true;
+    }
+
+    fn search_oam(&self, sprite_height: u8) -> Vec<Sprite> {
+        let line = u16::from(self.current_line) + 16;
+        (0..OAM_ENTRIES)
+            .map(|index| Sprite::new(&self.oam, index))
+            .filter(|sprite| {
+                let y = u16::from(sprite.y);
+                y <= line && line < y + u16::from(sprite_height)
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect()
+    }
+
+    fn sprite_height(io_registers: &Memory) -> u8 {
+        let lcd_control_register = io_registers.read(LCDC_REGISTER).unwrap();
+        if (lcd_control_register >> 2) & 1 == 0 {
+            8
+        } else {
+            16
+        }
+    }
+
+    fn select_line_sprites(&mut self, io_registers: &Memory) {
+        self.line_sprites.clear();
+        self.sprite_fetch_ticks = 0;
+        let lcd_control_register = io_registers.read(LCDC_REGISTER).unwrap();
+        if (lcd_control_register >> 1) & 1 == 0 {
+            return;
+        }
+        self.line_sprites = self.search_oam(Self::sprite_height(io_registers));
+        // The transfer reaches them from left to right, ties are fetched in OAM order
+        self.line_sprites
+            .sort_by_key(|sprite| (sprite.x, sprite.oam_index));
+    }
+
+    /// Fetches the next sprite once the transfer reaches its left edge and mixes it into the
+    /// FIFO. The background fetcher and the FIFO wait meanwhile, so every sprite lengthens mode
+    /// 3. Returns true while a fetch is running.
+    fn fetch_sprite(&mut self, io_registers: &Memory) -> bool {
+        let sprite = match self.line_sprites.first() {
+            Some(sprite) if i16::from(sprite.x) - 8 <= i16::from(self.current_pixel) => *sprite,
+            _ => return false,
+        };
+        self.sprite_fetch_ticks += 1;
+        if self.sprite_fetch_ticks < SPRITE_FETCH_TICKS {
+            return true;
+        }
+        self.sprite_fetch_ticks = 0;
+        self.line_sprites.remove(0);
+        let pixels = self.sprite_pixels(&sprite, io_registers);
+        let offset = i16::from(sprite.x) - 8 - i16::from(self.current_pixel);
+        // On the DMG the sprite with the lower X wins, which is the one fetched first. In CGB
+        // mode the lower OAM index wins.
+        self.pixel_fifo.merge_sprite(pixels, offset, self.cgb_mode);
+        true
+    }
+
+    /// The eight pixels of `sprite` in the current line from left to right.
+    fn sprite_pixels(&self, sprite: &Sprite, io_registers: &Memory) -> [SpritePixel; 8] {
+        let sprite_height = Self::sprite_height(io_registers);
+        let mut row =
+            self.current_line.wrapping_add(16).wrapping_sub(sprite.y) & (sprite_height - 1);
+        if sprite.y_flip() {
+            row = sprite_height - 1 - row;
+        }
+        let tile = if sprite_height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        let address = 0x8000 + u16::from(tile) * 0x10 + u16::from(row) * 0x2;
+        let vram = if self.cgb_mode && sprite.vram_bank() == 1 {
+            &self.memory_bank1
+        } else {
+            &self.memory
+        };
+        let data0 = vram.read(address).unwrap();
+        let data1 = vram.read(address + 1).unwrap();
+        let palette = if self.cgb_mode {
+            sprite.color_palette()
+        } else {
+            sprite.dmg_palette()
+        };
+        let mut pixels = [SpritePixel::default(); 8];
+        for (pixel, output) in (0..8u8).zip(pixels.iter_mut()) {
+            let bit = if sprite.x_flip() { pixel } else { 7 - pixel };
+            *output = SpritePixel {
+                color: (((data1 >> bit) & 1) << 1) | ((data0 >> bit) & 1),
+                palette,
+                behind_background: sprite.behind_background(),
+                oam_index: sprite.oam_index as u8,
+            };
+        }
+        pixels
     }
 
     pub fn pixel_transfer(&mut self, io_registers: &mut Memory) {
-        match (self.current_line as usize).cmp(&LINES_TO_DRAW) {
-            Ordering::Less => {
-                if self.current_pixel < 160 {
-                    if self.current_tick % 2 == 1 {
-                        self.fetcher
-                            .fetch_tile(&mut self.pixel_fifo, &self.memory, io_registers);
-                    }
-                    self.pixel_fifo.write_pixel(
-                        &mut self.lcd,
-                        &mut self.current_pixel,
-                        self.current_line,
-                    );
-                } else if self.current_pixel >= 160 {
-                    self.mode = PPUMode::HBlank;
-                    self.current_pixel = 0;
-                }
+        if self.current_pixel < 160 {
+            self.check_window(io_registers);
+            if self.fetch_sprite(io_registers) {
+                return;
             }
-            Ordering::Equal => {
-                self.mode = PPUMode::VBlank;
-                self.lcd.display();
+            if self.current_tick % 2 == 1 {
+                let vram = [&self.memory, &self.memory_bank1];
+                self.fetcher
+                    .fetch_tile(&mut self.pixel_fifo, vram, io_registers);
+            }
+            if let Some((background, sprite)) = self.pixel_fifo.shift_pixel() {
+                self.output_pixel(background, sprite, io_registers);
+                self.current_pixel += 1;
             }
-            Ordering::Greater => {
-                panic!()
+        } else {
+            self.mode = PPUMode::HBlank;
+            if self.window_in_line {
+                self.window_line += 1;
             }
         }
     }
 }
 
+impl PixelProcessingUnit {
+    /// Mixes the background and sprite pixel at the current position and sends it to the LCD.
+    fn output_pixel(
+        &mut self,
+        background: BackgroundPixel,
+        sprite: Option<SpritePixel>,
+        io_registers: &Memory,
+    ) {
+        let x = u32::from(self.current_pixel);
+        let y = u32::from(self.current_line);
+        if self.cgb_mode {
+            // With LCDC bit 0 cleared sprites are always drawn above the background
+            let master_priority = io_registers.read(LCDC_REGISTER).unwrap() & 1 == 1;
+            let color = match sprite {
+                Some(sprite)
+                    if !master_priority
+                        || background.color == 0
+                        || !(sprite.behind_background || background.priority) =>
+                {
+                    self.object_palettes.color(sprite.palette, sprite.color)
+                }
+                _ => self
+                    .background_palettes
+                    .color(background.palette, background.color),
+            };
+            self.lcd.set_color(x, y, color);
+        } else {
+            let (palettes, palette, register, color) = match sprite {
+                Some(sprite) if !(sprite.behind_background && background.color != 0) => {
+                    let register = if sprite.palette == 0 {
+                        OBP0_REGISTER
+                    } else {
+                        OBP1_REGISTER
+                    };
+                    (
+                        &self.object_palettes,
+                        sprite.palette,
+                        register,
+                        sprite.color,
+                    )
+                }
+                _ => (&self.background_palettes, 0, BGP_REGISTER, background.color),
+            };
+            let shade = (io_registers.read(register).unwrap() >> (color * 2)) & 0b11;
+            if self.compatibility_palettes {
+                let color = palettes.color(palette, shade);
+                self.lcd.set_color(x, y, color);
+            } else {
+                self.lcd.set_pixel(x, y, shade);
+            }
+        }
+    }
+
+    fn read_color_palettes(&self, address: u16) -> u8 {
+        match address {
+            BCPS_REGISTER => self.background_palettes.read_specification(),
+            BCPD_REGISTER => self.background_palettes.read_data(),
+            OCPS_REGISTER => self.object_palettes.read_specification(),
+            OCPD_REGISTER => self.object_palettes.read_data(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_color_palettes(&mut self, address: u16, value: u8) {
+        match address {
+            BCPS_REGISTER => self.background_palettes.write_specification(value),
+            BCPD_REGISTER => self.background_palettes.write_data(value),
+            OCPS_REGISTER => self.object_palettes.write_specification(value),
+            OCPD_REGISTER => self.object_palettes.write_data(value),
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl MapsMemory for PixelProcessingUnit {
     fn read(&self, address: u16) -> Result<u8, ()> {
         if self.memory.is_in_range(address) {
-            self.memory.read(address)
+            self.selected_vram().read(address)
         } else if self.oam.is_in_range(address) {
             self.oam.read(address)
         } else {
-            Err(())
+            match address {
+                STAT_REGISTER => Ok(self.read_stat()),
+                LY_REGISTER => Ok(self.current_line),
+                LYC_REGISTER => Ok(self.lyc),
+                VBK_REGISTER if self.cgb_mode => Ok(0xFE | self.vram_bank),
+                BCPS_REGISTER..=OCPD_REGISTER if self.cgb_mode => {
+                    Ok(self.read_color_palettes(address))
+                }
+                VBK_REGISTER | BCPS_REGISTER..=OCPD_REGISTER => Ok(0xFF),
+                _ => Err(()),
+            }
         }
     }
 
     fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
         if self.memory.is_in_range(address) {
-            self.memory.write(address, value)
+            self.selected_vram_mut().write(address, value)
         } else if self.oam.is_in_range(address) {
             self.oam.write(address, value)
         } else {
-            Err(())
+            match address {
+                STAT_REGISTER => self.stat_interrupt_select = value & 0b0111_1000,
+                LY_REGISTER => {}
+                LYC_REGISTER => self.lyc = value,
+                VBK_REGISTER => {
+                    if self.cgb_mode {
+                        self.vram_bank = value & 1;
+                    }
+                }
+                BCPS_REGISTER..=OCPD_REGISTER => {
+                    if self.cgb_mode {
+                        self.write_color_palettes(address, value);
+                    }
+                }
+                _ => return Err(()),
+            }
+            Ok(())
         }
     }
 
     fn is_in_range(&self, address: u16) -> bool {
         let vram = self.memory.is_in_range(address);
         let oam = self.oam.is_in_range(address);
-        vram | oam
+        let registers = matches!(
+            address,
+            STAT_REGISTER | LY_REGISTER | LYC_REGISTER | VBK_REGISTER | BCPS_REGISTER
+                ..=OCPD_REGISTER
+        );
+        vram | oam | registers
     }
 }
 
+#[derive(Copy, Clone, Default)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+    oam_index: u16,
+}
+
+impl Sprite {
+    fn new(oam: &Memory, index: u16) -> Sprite {
+        let address = 0xFE00 + index * 4;
+        Sprite {
+            y: oam.read(address).unwrap(),
+            x: oam.read(address + 1).unwrap(),
+            tile: oam.read(address + 2).unwrap(),
+            flags: oam.read(address + 3).unwrap(),
+            oam_index: index,
+        }
+    }
+
+    fn behind_background(&self) -> bool {
+        (self.flags >> 7) & 1 == 1
+    }
+
+    fn y_flip(&self) -> bool {
+        (self.flags >> 6) & 1 == 1
+    }
+
+    fn x_flip(&self) -> bool {
+        (self.flags >> 5) & 1 == 1
+    }
+
+    fn vram_bank(&self) -> u8 {
+        (self.flags >> 3) & 1
+    }
+
+    fn color_palette(&self) -> u8 {
+        self.flags & 0b111
+    }
+
+    fn dmg_palette(&self) -> u8 {
+        (self.flags >> 4) & 1
+    }
+}
+
+/// `palette` selects OBP0 or OBP1 on the DMG and is the palette number in CGB mode. Colour 0
+/// is transparent.
+#[derive(Copy, Clone, Default)]
+struct SpritePixel {
+    color: u8,
+    palette: u8,
+    behind_background: bool,
+    oam_index: u8,
+}
+
+#[derive(Copy, Clone)]
+struct BackgroundPixel {
+    color: u8,
+    palette: u8,
+    priority: bool,
+}
+
+/// Every pixel is queued with its colour and, next to it, the palette and priority from its
+/// tile's attributes. Sprites are mixed into a second layer that lines up with the next eight
+/// pixels sent to the LCD.
 struct PixelFifo {
     current_size: usize,
     color_queue: u32,
+    attribute_queue: u64,
+    discard: u8,
+    objects: [SpritePixel; 8],
 }
 
 impl PixelFifo {
@@ -193,27 +645,68 @@ impl PixelFifo {
         PixelFifo {
             current_size: 0,
             color_queue: 0,
+            attribute_queue: 0,
+            discard: 0,
+            objects: [SpritePixel::default(); 8],
+        }
+    }
+
+    /// Returns the next pixel for the LCD once the FIFO holds more than 8 pixels, together with
+    /// the sprite pixel above it.
+    pub fn shift_pixel(&mut self) -> Option<(BackgroundPixel, Option<SpritePixel>)> {
+        if self.current_size < 8 {
+            return None;
+        }
+        let background = self.pop();
+        if self.discard > 0 {
+            self.discard -= 1;
+            return None;
         }
+        let sprite = self.objects[0];
+        self.objects.copy_within(1.., 0);
+        self.objects[7] = SpritePixel::default();
+        Some((background, Some(sprite).filter(|sprite| sprite.color != 0)))
     }
 
-    pub fn write_pixel(&mut self, lcd: &mut Screen, pixel_in_line: &mut u8, line: u8) {
-        if self.current_size >= 8 {
-            let color = self.pop();
-            lcd.set_pixel(u32::from(*pixel_in_line), u32::from(line), color);
-            *pixel_in_line += 1;
+    /// Mixes a sprite into the object layer, `offset` being the position of its first pixel
+    /// relative to the next pixel sent to the LCD. Pixels already there are kept unless
+    /// `by_oam_index` is set and the new sprite has the lower OAM index.
+    pub fn merge_sprite(&mut self, pixels: [SpritePixel; 8], offset: i16, by_oam_index: bool) {
+        for (position, pixel) in (offset..).zip(pixels.iter()) {
+            if !(0..8).contains(&position) || pixel.color == 0 {
+                continue;
+            }
+            let current = &mut self.objects[position as usize];
+            if current.color == 0 || (by_oam_index && pixel.oam_index < current.oam_index) {
+                *current = *pixel;
+            }
         }
     }
 
-    fn pop(&mut self) -> u8 {
+    fn pop(&mut self) -> BackgroundPixel {
         let color = ((self.color_queue >> 30) & 0b11) as u8;
+        let attributes = ((self.attribute_queue >> 60) & 0b1111) as u8;
         self.color_queue <<= 2;
+        self.attribute_queue <<= 4;
         self.current_size -= 1;
-        color
+        BackgroundPixel {
+            color,
+            palette: attributes & ATTRIBUTE_PALETTE,
+            priority: attributes & 0b1000 != 0,
+        }
     }
 
-    pub fn push(&mut self, pixels: u16) {
+    pub fn push(&mut self, pixels: u16, attributes: u8) {
         assert!(self.current_size < 8);
-        self.color_queue |= u32::from(pixels) << ((16 - self.current_size) - 8);
+        self.color_queue |= u32::from(pixels) << (16 - 2 * self.current_size);
+        let priority = if attributes & ATTRIBUTE_PRIORITY != 0 {
+            0b1000
+        } else {
+            0
+        };
+        let attributes = u64::from((attributes & ATTRIBUTE_PALETTE) | priority);
+        let row = (0..8).fold(0u64, |row, _| (row << 4) | attributes);
+        self.attribute_queue |= row << (32 - 4 * self.current_size);
         self.current_size += 8;
     }
 
@@ -221,25 +714,44 @@ impl PixelFifo {
         self.current_size < 8
     }
 
-    fn reset(&mut self) {
+    /// Drops the next `pixels` pixels instead of shifting them out to the LCD.
+    pub fn discard(&mut self, pixels: u8) {
+        self.discard = pixels;
+    }
+
+    /// Drops the queued background pixels, the sprites mixed in so far stay.
+    fn reset_background(&mut self) {
         self.current_size = 0;
+        self.color_queue = 0;
+        self.attribute_queue = 0;
+        self.discard = 0;
+    }
+
+    fn reset(&mut self) {
+        self.reset_background();
+        self.objects = [SpritePixel::default(); 8];
     }
 }
 
 struct Fetcher {
+    cgb_mode: bool,
     current_tile_address: u16,
     current_map_line: u8,
+    window_line: Option<u8>,
     current_step: FetcherStep,
     current_tile_number: u16,
+    current_tile_row: u8,
+    current_attributes: u8,
     data0: u8,
     data1: u8,
 }
 
+#[derive(Copy, Clone)]
 enum FetcherStep {
-    ReadTile,
-    ReadData0,
-    ReadData1,
-    WriteData,
+    ReadTile = 0,
+    ReadData0 = 1,
+    ReadData1 = 2,
+    WriteData = 3,
 }
 
 impl FetcherStep {
@@ -251,24 +763,39 @@ impl FetcherStep {
             FetcherStep::WriteData => FetcherStep::ReadTile,
         }
     }
+
+    fn from_bits(bits: u8) -> Option<FetcherStep> {
+        match bits {
+            0 => Some(FetcherStep::ReadTile),
+            1 => Some(FetcherStep::ReadData0),
+            2 => Some(FetcherStep::ReadData1),
+            3 => Some(FetcherStep::WriteData),
+            _ => None,
+        }
+    }
 }
 
 impl Fetcher {
-    pub fn new() -> Fetcher {
+    pub fn new(cgb_mode: bool) -> Fetcher {
         Fetcher {
+            cgb_mode,
             current_step: FetcherStep::ReadTile,
             current_tile_number: 0,
             current_tile_address: 0,
+            current_tile_row: 0,
+            current_attributes: 0,
             data0: 0,
             data1: 0,
             current_map_line: 0,
+            window_line: None,
         }
     }
 
+    /// `vram` holds both VRAM banks, the second one is only used in CGB mode.
     pub fn fetch_tile(
         &mut self,
         pixel_fifo: &mut PixelFifo,
-        vram: &Memory,
+        vram: [&Memory; 2],
         io_registers: &mut Memory,
     ) {
         match self.current_step {
@@ -279,125 +806,277 @@ impl Fetcher {
         }
     }
 
-    fn read_tile(&mut self, vram: &Memory, io_registers: &mut Memory) {
+    fn read_tile(&mut self, vram: [&Memory; 2], io_registers: &mut Memory) {
         let lcd_control_register = io_registers.read(LCDC_REGISTER).unwrap();
-        let bg_map_address = if (lcd_control_register >> 3) & 1 == 0 {
+        let (map_select_bit, x, y) = match self.window_line {
+            Some(window_line) => (6, self.current_tile_address, window_line),
+            None => {
+                let scx = io_registers.read(SCX_REGISTER).unwrap();
+                let scy = io_registers.read(SCY_REGISTER).unwrap();
+                let x = (u16::from(scx / 8) + self.current_tile_address) % TILES_IN_MAP_LINE;
+                (3, x, self.current_map_line.wrapping_add(scy))
+            }
+        };
+        let map_address = if (lcd_control_register >> map_select_bit) & 1 == 0 {
             0x9800
         } else {
             0x9C00
         };
-        let _scx = u16::from(io_registers.read(SCX_REGISTER).unwrap());
-        let scy = io_registers.read(SCY_REGISTER).unwrap();
-        let tile_map_address = bg_map_address
-            + self.current_tile_address
-            + u16::from((self.current_map_line.wrapping_add(scy) as u8) / 8) * 0x20;
-        // println!("line: {:#04x} | scy: {} | bg_map_address: {:#06x} | tile: {:#06x} | translated line: {:#04x} | tile_map_address: {:#06x}", self.current_map_line, scy, bg_map_address, self.current_tile_address, ((self.current_map_line.wrapping_add(scy) / 8) as u16), tile_map_address);
-        self.current_tile_number = u16::from(vram.read(tile_map_address).unwrap());
+        let tile_map_address = map_address + x + u16::from(y / 8) * TILES_IN_MAP_LINE;
+        self.current_tile_number = u16::from(vram[0].read(tile_map_address).unwrap());
+        self.current_attributes = if self.cgb_mode {
+            vram[1].read(tile_map_address).unwrap()
+        } else {
+            0
+        };
+        self.current_tile_row = if self.current_attributes & ATTRIBUTE_Y_FLIP != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
         self.current_tile_address += 1;
         self.current_step = self.current_step.next();
     }
 
-    fn read_data0(&mut self, vram: &Memory, io_registers: &mut Memory) {
+    fn tile_data_address(&self, io_registers: &Memory) -> u16 {
         let lcd_control_register = io_registers.read(LCDC_REGISTER).unwrap();
-        let bg_tiles_address = if (lcd_control_register >> 4) & 1 == 0 {
-            0x9000
-        } else {
-            0x8000
-        };
-        if bg_tiles_address == 0x8000 {
-            self.data0 = vram
-                .read(
-                    bg_tiles_address
-                        + u16::from((self.current_map_line % 8) * 0x2)
-                        + (self.current_tile_number * 0x10),
-                )
-                .unwrap();
-            if self.current_map_line % 8 == 0 {
-                // println!("data0 ({:#06x}), {:#010b}", bg_tiles_address +
-                // ((self.current_map_line % 8) * 0x2) as u16 + (self.current_tile_number *
-                // 0x10), self.data0);
-            }
-        } else if bg_tiles_address == 0x9000 {
-            // Todo: Recheck later
-            let mapped_tile = self.current_tile_number as i8;
-            self.data0 = vram
-                .read(
-                    (i32::from(bg_tiles_address)
-                        + ((i32::from(self.current_map_line) % 8) * 0x2)
-                        + (i32::from(mapped_tile) * 0x10) as i32) as u16,
-                )
-                .unwrap();
+        let row = u16::from(self.current_tile_row) * 0x2;
+        if (lcd_control_register >> 4) & 1 == 1 {
+            0x8000 + self.current_tile_number * 0x10 + row
         } else {
-            unimplemented!()
+            let mapped_tile = i32::from(self.current_tile_number as u8 as i8);
+            (0x9000 + mapped_tile * 0x10) as u16 + row
         }
-        self.current_step = self.current_step.next();
     }
 
-    fn read_data1(&mut self, pixel_fifo: &mut PixelFifo, vram: &Memory, io_registers: &mut Memory) {
-        let lcd_control_register = io_registers.read(LCDC_REGISTER).unwrap();
-        let bg_tiles_address = if (lcd_control_register >> 4) & 1 == 0 {
-            0x9000
+    fn tile_data_bank<'a>(&self, vram: [&'a Memory; 2]) -> &'a Memory {
+        if self.current_attributes & ATTRIBUTE_TILE_BANK != 0 {
+            vram[1]
         } else {
-            0x8000
-        } + 1;
-        if bg_tiles_address == 0x8001 {
-            self.data1 = vram
-                .read(
-                    bg_tiles_address
-                        + u16::from((self.current_map_line % 8) * 0x2)
-                        + (self.current_tile_number * 0x10),
-                )
-                .unwrap();
-            if self.current_map_line % 8 == 0 {
-                // println!("data1 ({:#06x}), {:#010b}", bg_tiles_address +
-                // ((self.current_map_line % 8) * 0x2) as u16 + (self.current_tile_number *
-                // 0x10), self.data1);
-            }
-        } else if bg_tiles_address == 0x9001 {
-            // Todo: Recheck later
-            let _mapped_tile = i32::from(self.current_tile_number);
-            self.data1 = vram
-                .read(
-                    (i32::from(bg_tiles_address)
-                        + ((i32::from(self.current_map_line) % 8) * 0x2)
-                        + i32::from(self.current_tile_number * 0x10)) as u16,
-                )
-                .unwrap();
-        } else {
-            unimplemented!()
+            vram[0]
         }
+    }
+
+    fn read_data0(&mut self, vram: [&Memory; 2], io_registers: &mut Memory) {
+        let address = self.tile_data_address(io_registers);
+        self.data0 = self.tile_data_bank(vram).read(address).unwrap();
+        self.current_step = self.current_step.next();
+    }
+
+    fn read_data1(
+        &mut self,
+        pixel_fifo: &mut PixelFifo,
+        vram: [&Memory; 2],
+        io_registers: &mut Memory,
+    ) {
+        let address = self.tile_data_address(io_registers) + 1;
+        self.data1 = self.tile_data_bank(vram).read(address).unwrap();
         self.current_step = self.current_step.next();
         self.write_data(pixel_fifo);
     }
 
     fn write_data(&mut self, pixel_fifo: &mut PixelFifo) {
         if pixel_fifo.is_free() {
-            pixel_fifo.push(self.combine_pixels());
+            pixel_fifo.push(self.combine_pixels(), self.current_attributes);
             self.current_step = self.current_step.next();
-            if self.current_tile_address % TILES_IN_LINES == 0 && self.current_tile_address != 0 {
-                self.current_tile_address = 0;
-                self.current_map_line = (self.current_map_line + 1) % LINES_TO_DRAW as u8;
-            };
         }
     }
 
-    fn combine_pixels(&mut self) -> u16 {
+    fn combine_pixels(&self) -> u16 {
+        let (data0, data1) = if self.current_attributes & ATTRIBUTE_X_FLIP != 0 {
+            (self.data0.reverse_bits(), self.data1.reverse_bits())
+        } else {
+            (self.data0, self.data1)
+        };
         let mut result: u16 = 0;
-        result |= u16::from(((self.data1 >> 7) & 1) << 1 | ((self.data0 >> 7) & 1)) << 14;
-        result |= u16::from(((self.data1 >> 6) & 1) << 1 | ((self.data0 >> 6) & 1)) << 12;
-        result |= u16::from(((self.data1 >> 5) & 1) << 1 | ((self.data0 >> 5) & 1)) << 10;
-        result |= u16::from(((self.data1 >> 4) & 1) << 1 | ((self.data0 >> 4) & 1)) << 8;
-        result |= u16::from(((self.data1 >> 3) & 1) << 1 | ((self.data0 >> 3) & 1)) << 6;
-        result |= u16::from(((self.data1 >> 2) & 1) << 1 | ((self.data0 >> 2) & 1)) << 4;
-        result |= u16::from(((self.data1 >> 1) & 1) << 1 | ((self.data0 >> 1) & 1)) << 2;
-        result |= u16::from(((self.data1) & 1) << 1 | ((self.data0) & 1));
+        result |= u16::from(((data1 >> 7) & 1) << 1 | ((data0 >> 7) & 1)) << 14;
+        result |= u16::from(((data1 >> 6) & 1) << 1 | ((data0 >> 6) & 1)) << 12;
+        result |= u16::from(((data1 >> 5) & 1) << 1 | ((data0 >> 5) & 1)) << 10;
+        result |= u16::from(((data1 >> 4) & 1) << 1 | ((data0 >> 4) & 1)) << 8;
+        result |= u16::from(((data1 >> 3) & 1) << 1 | ((data0 >> 3) & 1)) << 6;
+        result |= u16::from(((data1 >> 2) & 1) << 1 | ((data0 >> 2) & 1)) << 4;
+        result |= u16::from(((data1 >> 1) & 1) << 1 | ((data0 >> 1) & 1)) << 2;
+        result |= u16::from(((data1) & 1) << 1 | ((data0) & 1));
         // println!("Combined Pixels: {:#018b}", result);
         result
     }
 
-    fn reset(&mut self) {
+    /// Restarts fetching at the leftmost background tile of `line`.
+    fn start_line(&mut self, line: u8) {
         self.current_tile_address = 0;
-        self.current_map_line = 0;
+        self.current_map_line = line;
+        self.window_line = None;
         self.current_step = FetcherStep::ReadTile;
     }
+
+    /// Restarts fetching at the leftmost window tile, `window_line` being the window's own line.
+    fn start_window(&mut self, window_line: u8) {
+        self.current_tile_address = 0;
+        self.window_line = Some(window_line);
+        self.current_step = FetcherStep::ReadTile;
+    }
+
+    fn is_fetching_window(&self) -> bool {
+        self.window_line.is_some()
+    }
+
+    fn reset(&mut self) {
+        self.start_line(0);
+    }
+}
+
+impl SaveState for PixelProcessingUnit {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.memory.save_state(writer);
+        self.memory_bank1.save_state(writer);
+        writer.write_u8(self.vram_bank);
+        writer.write_bool(self.compatibility_palettes);
+        self.oam.save_state(writer);
+        self.background_palettes.save_state(writer);
+        self.object_palettes.save_state(writer);
+        self.lcd.save_state(writer);
+        self.pixel_fifo.save_state(writer);
+        self.fetcher.save_state(writer);
+        writer.write_u8(self.line_sprites.len() as u8);
+        for sprite in &self.line_sprites {
+            sprite.save_state(writer);
+        }
+        writer.write_u8(self.sprite_fetch_ticks);
+        writer.write_bool(self.window_y_reached);
+        writer.write_u8(self.window_line);
+        writer.write_bool(self.window_in_line);
+        writer.write_u8(self.stat_interrupt_select);
+        writer.write_u8(self.lyc);
+        writer.write_bool(self.stat_line);
+        writer.write_u32(self.current_tick as u32);
+        writer.write_u8(self.current_pixel);
+        writer.write_u8(self.current_line);
+        writer.write_u8(self.mode as u8);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(reader)?;
+        self.memory_bank1.load_state(reader)?;
+        self.vram_bank = reader.read_u8()? & 1;
+        self.compatibility_palettes = reader.read_bool()?;
+        self.oam.load_state(reader)?;
+        self.background_palettes.load_state(reader)?;
+        self.object_palettes.load_state(reader)?;
+        self.lcd.load_state(reader)?;
+        self.pixel_fifo.load_state(reader)?;
+        self.fetcher.load_state(reader)?;
+        let sprites = usize::from(reader.read_u8()?);
+        if sprites > MAX_SPRITES_PER_LINE {
+            return Err(StateError::Corrupted);
+        }
+        self.line_sprites.clear();
+        for _ in 0..sprites {
+            let mut sprite = Sprite::default();
+            sprite.load_state(reader)?;
+            self.line_sprites.push(sprite);
+        }
+        self.sprite_fetch_ticks = reader.read_u8()?;
+        if self.sprite_fetch_ticks >= SPRITE_FETCH_TICKS {
+            return Err(StateError::Corrupted);
+        }
+        self.window_y_reached = reader.read_bool()?;
+        self.window_line = reader.read_u8()?;
+        self.window_in_line = reader.read_bool()?;
+        self.stat_interrupt_select = reader.read_u8()? & 0b0111_1000;
+        self.lyc = reader.read_u8()?;
+        self.stat_line = reader.read_bool()?;
+        self.current_tick = reader.read_u32()? as usize;
+        self.current_pixel = reader.read_u8()?;
+        self.current_line = reader.read_u8()?;
+        self.mode = PPUMode::from_bits(reader.read_u8()?).ok_or(StateError::Corrupted)?;
+        if self.current_tick >= TICKS_PER_CYCLE || self.current_pixel as usize > PIXELS_IN_LINE {
+            return Err(StateError::Corrupted);
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for PixelFifo {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_size as u8);
+        writer.write_u32(self.color_queue);
+        writer.write_u64(self.attribute_queue);
+        writer.write_u8(self.discard);
+        for pixel in &self.objects {
+            writer.write_u8(pixel.color);
+            writer.write_u8(pixel.palette);
+            writer.write_bool(pixel.behind_background);
+            writer.write_u8(pixel.oam_index);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_size = usize::from(reader.read_u8()?);
+        self.color_queue = reader.read_u32()?;
+        self.attribute_queue = reader.read_u64()?;
+        self.discard = reader.read_u8()?;
+        for pixel in self.objects.iter_mut() {
+            pixel.color = reader.read_u8()? & 0b11;
+            pixel.palette = reader.read_u8()?;
+            pixel.behind_background = reader.read_bool()?;
+            pixel.oam_index = reader.read_u8()?;
+        }
+        if self.current_size > 16 {
+            return Err(StateError::Corrupted);
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for Sprite {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.y);
+        writer.write_u8(self.x);
+        writer.write_u8(self.tile);
+        writer.write_u8(self.flags);
+        writer.write_u8(self.oam_index as u8);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.y = reader.read_u8()?;
+        self.x = reader.read_u8()?;
+        self.tile = reader.read_u8()?;
+        self.flags = reader.read_u8()?;
+        self.oam_index = u16::from(reader.read_u8()?);
+        if self.oam_index >= OAM_ENTRIES {
+            return Err(StateError::Corrupted);
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for Fetcher {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.current_tile_address);
+        writer.write_u8(self.current_map_line);
+        writer.write_bool(self.window_line.is_some());
+        writer.write_u8(self.window_line.unwrap_or(0));
+        writer.write_u8(self.current_step as u8);
+        writer.write_u16(self.current_tile_number);
+        writer.write_u8(self.current_tile_row);
+        writer.write_u8(self.current_attributes);
+        writer.write_u8(self.data0);
+        writer.write_u8(self.data1);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_tile_address = reader.read_u16()? % TILES_IN_MAP_LINE;
+        self.current_map_line = reader.read_u8()?;
+        let window = reader.read_bool()?;
+        let window_line = reader.read_u8()?;
+        self.window_line = if window { Some(window_line) } else { None };
+        self.current_step =
+            FetcherStep::from_bits(reader.read_u8()?).ok_or(StateError::Corrupted)?;
+        self.current_tile_number = reader.read_u16()?;
+        self.current_tile_row = reader.read_u8()? % 8;
+        self.current_attributes = reader.read_u8()?;
+        self.data0 = reader.read_u8()?;
+        self.data1 = reader.read_u8()?;
+        Ok(())
+    }
 }
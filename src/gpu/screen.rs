@@ -1,3 +1,5 @@
+use super::palette::Palette;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 use image::{ImageBuffer, Rgba};
 use std::{cell::RefCell, rc::Rc};
 
@@ -13,6 +15,8 @@ pub(crate) struct Screen {
     lcd_fetcher: Rc<RefCell<ScreenFetcher>>,
     image: ImageBuffer<Rgba<u8>, Vec<u8>>,
     calc_pos: u32,
+    palette: Palette,
+    color_correction: bool,
 }
 
 impl Screen {
@@ -22,22 +26,36 @@ impl Screen {
             image,
             lcd_fetcher,
             calc_pos: 0,
+            palette: Palette::default(),
+            color_correction: false,
         }
     }
 
-    pub fn set_pixel(&mut self, x: u32, y: u32, color: u8) {
-        // Todo: correct colors
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: bool) {
+        self.color_correction = color_correction;
+    }
+
+    /// `shade` is the colour after it went through BGP, OBP0 or OBP1.
+    pub fn set_pixel(&mut self, x: u32, y: u32, shade: u8) {
+        let pixel = self.palette.color(shade);
+        self.put_pixel(x, y, pixel);
+    }
+
+    /// `color` is a BGR555 colour from the CGB palette RAM.
+    pub fn set_color(&mut self, x: u32, y: u32, color: u16) {
+        let pixel = convert_color(color, self.color_correction);
+        self.put_pixel(x, y, pixel);
+    }
+
+    fn put_pixel(&mut self, x: u32, y: u32, pixel: Rgba<u8>) {
         if self.calc_pos == PIXELS {
             self.calc_pos = 0;
         }
         assert_eq!(self.calc_pos, HOR_PIXELS * y + x);
-        let pixel = match color {
-            0b00 => Rgba([255u8, 255u8, 255u8, 255u8]),
-            0b01 => Rgba([180u8, 180u8, 180u8, 255u8]),
-            0b10 => Rgba([90u8, 90u8, 90u8, 255u8]),
-            0b11 => Rgba([0u8, 0u8, 0u8, 255u8]),
-            _ => panic!("That's not a color"),
-        };
         self.calc_pos += 1;
         self.image.put_pixel(x, y, pixel)
     }
@@ -47,6 +65,44 @@ impl Screen {
     }
 }
 
+/// Scales the 5-bit channels to 8 bits. The correction mixes the channels and darkens the
+/// brightest values the way the CGB LCD does, so colours chosen for the hardware don't look
+/// oversaturated.
+fn convert_color(color: u16, color_correction: bool) -> Rgba<u8> {
+    let r = u32::from(color & 0x1F);
+    let g = u32::from((color >> 5) & 0x1F);
+    let b = u32::from((color >> 10) & 0x1F);
+    if color_correction {
+        let mix = |value: u32| (value.min(960) >> 2) as u8;
+        Rgba([
+            mix(r * 26 + g * 4 + b * 2),
+            mix(g * 24 + b * 8),
+            mix(r * 6 + g * 4 + b * 22),
+            255,
+        ])
+    } else {
+        let scale = |value: u32| ((value << 3) | (value >> 2)) as u8;
+        Rgba([scale(r), scale(g), scale(b), 255])
+    }
+}
+
+/// The palette and colour correction are frontend settings and not part of the state.
+impl SaveState for Screen {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.image);
+        writer.write_u32(self.calc_pos);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.image)?;
+        self.calc_pos = reader.read_u32()?;
+        if self.calc_pos > PIXELS {
+            return Err(StateError::Corrupted);
+        }
+        Ok(())
+    }
+}
+
 pub(crate) struct ScreenFetcher {
     image: ImageBuffer<Rgba<u8>, Vec<u8>>,
 }
@@ -0,0 +1,152 @@
+use crate::{
+    mem::memory::MapsMemory,
+    processor::interrupt_controller::{Interrupt, InterruptController},
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+const JOYPAD_REGISTER: u16 = 0xFF00;
+
+const SELECT_DIRECTION_BIT: u8 = 4;
+const SELECT_ACTION_BIT: u8 = 5;
+const LINES_MASK: u8 = 0b0000_1111;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    fn line(self) -> u8 {
+        match self {
+            Button::Right | Button::A => 0,
+            Button::Left | Button::B => 1,
+            Button::Up | Button::Select => 2,
+            Button::Down | Button::Start => 3,
+        }
+    }
+
+    fn is_direction(self) -> bool {
+        match self {
+            Button::Right | Button::Left | Button::Up | Button::Down => true,
+            Button::A | Button::B | Button::Select | Button::Start => false,
+        }
+    }
+}
+
+/// P1 register. All lines are active low: a pressed button reads as 0 while its group is
+/// selected by writing 0 to the corresponding select bit.
+pub(crate) struct Joypad {
+    select: u8,
+    directions: u8,
+    actions: u8,
+    falling_edge: bool,
+    interrupt_pending: bool,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0b0011_0000,
+            directions: LINES_MASK,
+            actions: LINES_MASK,
+            falling_edge: false,
+            interrupt_pending: false,
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let before = self.lines();
+        let group = if button.is_direction() {
+            &mut self.directions
+        } else {
+            &mut self.actions
+        };
+        if pressed {
+            *group &= !(1 << button.line());
+        } else {
+            *group |= 1 << button.line();
+        }
+        self.check_transition(before);
+    }
+
+    /// Requests the joypad interrupt if a selected line went low since the last step.
+    pub fn step(&mut self, interrupt: &mut InterruptController) {
+        if std::mem::replace(&mut self.interrupt_pending, false) {
+            interrupt.request(Interrupt::Joypad);
+        }
+    }
+
+    /// True if a selected input line went low since the last call, which is what ends STOP.
+    pub fn take_falling_edge(&mut self) -> bool {
+        std::mem::replace(&mut self.falling_edge, false)
+    }
+
+    fn check_transition(&mut self, before: u8) {
+        if before & !self.lines() & LINES_MASK != 0 {
+            self.interrupt_pending = true;
+            self.falling_edge = true;
+        }
+    }
+
+    fn lines(&self) -> u8 {
+        let mut lines = LINES_MASK;
+        if (self.select >> SELECT_DIRECTION_BIT) & 1 == 0 {
+            lines &= self.directions;
+        }
+        if (self.select >> SELECT_ACTION_BIT) & 1 == 0 {
+            lines &= self.actions;
+        }
+        lines
+    }
+}
+
+impl MapsMemory for Joypad {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            JOYPAD_REGISTER => Ok(0b1100_0000 | self.select | self.lines()),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            JOYPAD_REGISTER => {
+                let before = self.lines();
+                self.select = value & 0b0011_0000;
+                self.check_transition(before);
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        address == JOYPAD_REGISTER
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.select);
+        writer.write_u8(self.directions);
+        writer.write_u8(self.actions);
+        writer.write_bool(self.falling_edge);
+        writer.write_bool(self.interrupt_pending);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.select = reader.read_u8()?;
+        self.directions = reader.read_u8()?;
+        self.actions = reader.read_u8()?;
+        self.falling_edge = reader.read_bool()?;
+        self.interrupt_pending = reader.read_bool()?;
+        Ok(())
+    }
+}
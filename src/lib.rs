@@ -1,12 +1,24 @@
 #[macro_use]
 extern crate log;
 
+mod apu;
 mod debug;
 mod emulator;
 mod gpu;
+mod input;
 mod mem;
 mod processor;
+mod state;
 mod util;
 
-pub use emulator::gameboy::{Emulator, Gameboy};
-pub use mem::cartridge::Cartridge;
+pub use apu::wav::write_wav;
+pub use emulator::{
+    gameboy::{Emulator, Gameboy},
+    model::Model,
+};
+pub use gpu::{compatibility::CompatibilityPalette, palette::Palette};
+pub use input::joypad::Button;
+pub use mem::cartridge::{
+    Cartridge, CartridgeError, CartridgeInfo, CgbSupport, Clock, Destination, Mapper,
+};
+pub use state::StateError;
@@ -1,38 +1,233 @@
-use super::memory::{MapsMemory, Memory};
+use super::{
+    licensee::{self, USE_NEW_LICENSEE},
+    memory::{MapsMemory, Memory},
+};
+use crate::state::{self, SaveState, StateError, StateReader, StateWriter};
+use std::{
+    convert::TryInto,
+    error::Error,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const LOGO_START: usize = 0x104;
+pub(crate) const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+const RAM_BANK_SIZE: u16 = 0x2000;
+const HEADER_END: usize = 0x150;
+
+// Live and latched RTC registers as 32-bit values followed by a 64-bit Unix timestamp
+const RTC_FOOTER_SIZE: usize = 48;
+const RTC_FOOTER_SIZE_32BIT_TIMESTAMP: usize = 44;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CartridgeError {
+    /// The ROM is shorter than its header or than the size the header declares
+    Truncated { expected: usize, actual: usize },
+    /// The cartridge type at 0x147 is not supported
+    UnknownMapper(u8),
+    /// The ROM size code at 0x148 is invalid
+    InvalidRomSize(u8),
+    /// The RAM size code at 0x149 is invalid
+    InvalidRamSize(u8),
+    /// The header checksum at 0x14D doesn't match the header
+    HeaderChecksum { expected: u8, actual: u8 },
+    /// The global checksum at 0x14E-0x14F doesn't match the ROM, only checked by
+    /// `Cartridge::new_strict`
+    GlobalChecksum { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "ROM is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            CartridgeError::UnknownMapper(code) => {
+                write!(f, "unsupported cartridge type {:#04x}", code)
+            }
+            CartridgeError::InvalidRomSize(code) => write!(f, "invalid ROM size {:#04x}", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "invalid RAM size {:#04x}", code),
+            CartridgeError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header checksum mismatch: expected {:#04x}, got {:#04x}",
+                expected, actual
+            ),
+            CartridgeError::GlobalChecksum { expected, actual } => write!(
+                f,
+                "global checksum mismatch: expected {:#06x}, got {:#06x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+/// The memory bank controller of a cartridge.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mapper {
+    None,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+}
+
+/// How a game uses the Game Boy Color, from the flag at 0x143.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CgbSupport {
+    /// A DMG game, run in compatibility mode on a CGB
+    Unsupported,
+    /// The game uses CGB features but still runs on a DMG
+    Enhanced,
+    /// The game only runs on a CGB
+    Required,
+}
+
+impl CgbSupport {
+    fn new(flag: u8) -> CgbSupport {
+        match flag & 0xC0 {
+            0xC0 => CgbSupport::Required,
+            0x80 => CgbSupport::Enhanced,
+            _ => CgbSupport::Unsupported,
+        }
+    }
+}
+
+/// The market a cartridge was sold in, from the code at 0x14A.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Destination {
+    Japanese,
+    Overseas,
+}
+
+/// The decoded header of a cartridge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeInfo {
+    pub title: String,
+    /// The four character manufacturer code newer games store after an 11 character title
+    pub manufacturer: Option<String>,
+    pub mapper: Mapper,
+    pub battery: bool,
+    pub rtc: bool,
+    pub rumble: bool,
+    /// Size of the ROM in bytes
+    pub rom_size: usize,
+    /// Size of the external RAM in bytes, including the built-in RAM of the MBC2
+    pub ram_size: usize,
+    pub cgb: CgbSupport,
+    /// Whether the game supports the Super Game Boy, from the flag at 0x146
+    pub sgb: bool,
+    pub destination: Destination,
+    pub old_licensee_code: u8,
+    pub old_licensee: Option<&'static str>,
+    /// The two character code at 0x144-0x145, only used if the old code is 0x33
+    pub new_licensee_code: Option<String>,
+    pub new_licensee: Option<&'static str>,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    /// The hardware never checks the global checksum, so patched ROMs and homebrew often get it
+    /// wrong
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeInfo {
+    /// The publisher of the game, taken from the new licensee code if the header uses it.
+    pub fn licensee(&self) -> Option<&'static str> {
+        if self.old_licensee_code == USE_NEW_LICENSEE {
+            self.new_licensee
+        } else {
+            self.old_licensee
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 struct CartridgeHeader {
     title: String,
-    manufacturer: String,
-    licensee_code: u16,
+    title_bytes: [u8; 16],
+    manufacturer: Option<String>,
+    new_licensee_code: String,
     old_licensee_code: u8,
+    cgb_flag: u8,
+    sgb_flag: u8,
     cartridge_type: CartridgeType,
     rom_size: RomSize,
     ram_size: RamSize,
-    destination: String,
+    destination: Destination,
     version: u8,
     checksum: u8,
     global_checksum: u16,
+    actual_global_checksum: u16,
 }
 
 impl CartridgeHeader {
-    pub fn new(rom: &[u8]) -> CartridgeHeader {
-        let title = Self::extract_title(rom);
-        let manufacturer = String::new();
-        let licensee_code = (u16::from(rom[0x144]) << 8) + u16::from(rom[0x145]);
-        let cartridge_type = CartridgeType::new(rom[0x147]);
-        let rom_size = RomSize::new(rom[0x148]);
-        let ram_size = RamSize::new(rom[0x149]);
-        let destination = String::new();
+    pub fn new(data: &[u8]) -> Result<CartridgeHeader, CartridgeError> {
+        if data.len() < HEADER_END {
+            return Err(CartridgeError::Truncated {
+                expected: HEADER_END,
+                actual: data.len(),
+            });
+        }
+        let offset = Self::header_offset(data);
+        let rom = &data[offset..];
+        let checksum = rom[0x14D];
+        let header_checksum = Self::header_checksum(rom);
+        if header_checksum != checksum {
+            return Err(CartridgeError::HeaderChecksum {
+                expected: checksum,
+                actual: header_checksum,
+            });
+        }
+        let cgb_flag = rom[0x143];
+        let manufacturer = Self::extract_manufacturer(rom);
+        let title_end = if manufacturer.is_some() {
+            0x13F
+        } else if CgbSupport::new(cgb_flag) != CgbSupport::Unsupported {
+            0x143
+        } else {
+            0x144
+        };
+        let title = Self::extract_text(&rom[0x134..title_end]);
+        let mut title_bytes = [0; 16];
+        title_bytes.copy_from_slice(&rom[0x134..=0x143]);
+        let new_licensee_code = Self::extract_text(&rom[0x144..=0x145]);
+        let sgb_flag = rom[0x146];
+        let cartridge_type = CartridgeType::new(rom[0x147])?;
+        let rom_size = RomSize::new(rom[0x148])?;
+        let ram_size = RamSize::new(rom[0x149])?;
+        if data.len() < rom_size.bytes() {
+            return Err(CartridgeError::Truncated {
+                expected: rom_size.bytes(),
+                actual: data.len(),
+            });
+        }
+        let destination = if rom[0x14A] == 0x00 {
+            Destination::Japanese
+        } else {
+            Destination::Overseas
+        };
         let old_licensee_code = rom[0x14B];
         let version = rom[0x14C];
-        let checksum = rom[0x14D];
         let global_checksum = (u16::from(rom[0x14E]) << 8) + u16::from(rom[0x14F]);
+        let actual_global_checksum = Self::global_checksum(data, offset);
         let header = CartridgeHeader {
             title,
+            title_bytes,
             manufacturer,
-            licensee_code,
+            new_licensee_code,
             old_licensee_code,
+            cgb_flag,
+            sgb_flag,
             cartridge_type,
             rom_size,
             ram_size,
@@ -40,21 +235,97 @@ impl CartridgeHeader {
             version,
             checksum,
             global_checksum,
+            actual_global_checksum,
         };
         info!("Load Rom: {:?}", header);
 
-        header
+        Ok(header)
     }
 
-    fn extract_title(rom: &[u8]) -> String {
-        let mut title = Vec::new();
-        for &character in rom.iter().take(0x144).skip(0x134) {
-            if character == 0 {
-                break;
-            }
-            title.push(character)
+    /// The value the boot ROM compares with 0x14D before starting the game.
+    fn header_checksum(rom: &[u8]) -> u8 {
+        rom[0x134..0x14D]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1))
+    }
+
+    /// The sum of all bytes of the ROM except for the global checksum itself.
+    fn global_checksum(data: &[u8], offset: usize) -> u16 {
+        let checksum = offset + 0x14E..=offset + 0x14F;
+        data.iter()
+            .enumerate()
+            .filter(|(index, _)| !checksum.contains(index))
+            .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(u16::from(byte)))
+    }
+
+    /// MMM01 multicarts keep the menu, and with it the header describing the whole cartridge, in
+    /// the last 32 KB of the ROM.
+    fn header_offset(rom: &[u8]) -> usize {
+        let is_mmm01 = |offset: usize| matches!(rom.get(offset + 0x147), Some(0x0B..=0x0D));
+        if rom.len() > 0x8000 && !is_mmm01(0) && is_mmm01(rom.len() - 0x8000) {
+            rom.len() - 0x8000
+        } else {
+            0
+        }
+    }
+
+    /// CGB games with an 11 character title follow it with an upper case manufacturer code.
+    fn extract_manufacturer(rom: &[u8]) -> Option<String> {
+        let code = &rom[0x13F..0x143];
+        let is_code = code
+            .iter()
+            .all(|character| character.is_ascii_uppercase() || character.is_ascii_digit());
+        if CgbSupport::new(rom[0x143]) != CgbSupport::Unsupported && is_code {
+            Some(Self::extract_text(code))
+        } else {
+            None
+        }
+    }
+
+    fn extract_text(bytes: &[u8]) -> String {
+        let text: Vec<u8> = bytes
+            .iter()
+            .cloned()
+            .take_while(|&character| character != 0)
+            .collect();
+        String::from_utf8(text).unwrap_or_else(|_| "".to_string())
+    }
+
+    fn info(&self) -> CartridgeInfo {
+        let cartridge_type = self.cartridge_type;
+        let ram_size = match cartridge_type {
+            CartridgeType::MBC2 { .. } => 0x200,
+            _ if cartridge_type.ram() => self.ram_size.bytes(),
+            _ => 0,
+        };
+        let new_licensee_code = if self.old_licensee_code == USE_NEW_LICENSEE {
+            Some(self.new_licensee_code.clone())
+        } else {
+            None
+        };
+        CartridgeInfo {
+            title: self.title.clone(),
+            manufacturer: self.manufacturer.clone(),
+            mapper: cartridge_type.mapper(),
+            battery: cartridge_type.battery(),
+            rtc: matches!(cartridge_type, CartridgeType::MBC3 { timer: true, .. }),
+            rumble: matches!(cartridge_type, CartridgeType::MBC5 { rumble: true, .. }),
+            rom_size: self.rom_size.bytes(),
+            ram_size,
+            cgb: CgbSupport::new(self.cgb_flag),
+            sgb: self.sgb_flag == 0x03,
+            destination: self.destination,
+            old_licensee_code: self.old_licensee_code,
+            old_licensee: licensee::old_licensee_name(self.old_licensee_code),
+            new_licensee: new_licensee_code
+                .as_deref()
+                .and_then(licensee::new_licensee_name),
+            new_licensee_code,
+            version: self.version,
+            header_checksum: self.checksum,
+            global_checksum: self.global_checksum,
+            global_checksum_valid: self.global_checksum == self.actual_global_checksum,
         }
-        String::from_utf8(title).unwrap_or_else(|_| "".to_string())
     }
 }
 
@@ -80,11 +351,16 @@ enum CartridgeType {
         ram: bool,
         battery: bool,
     },
+    MBC5 {
+        rumble: bool,
+        ram: bool,
+        battery: bool,
+    },
 }
 
 impl CartridgeType {
-    pub fn new(code: u8) -> CartridgeType {
-        match code {
+    pub fn new(code: u8) -> Result<CartridgeType, CartridgeError> {
+        let cartridge_type = match code {
             0x00 => CartridgeType::MBCNone {
                 ram: false,
                 battery: false,
@@ -103,6 +379,10 @@ impl CartridgeType {
             },
             0x05 => CartridgeType::MBC2 { battery: false },
             0x06 => CartridgeType::MBC2 { battery: true },
+            0x0B => CartridgeType::MMM01 {
+                ram: false,
+                battery: false,
+            },
             0x0C => CartridgeType::MMM01 {
                 ram: true,
                 battery: false,
@@ -136,7 +416,71 @@ impl CartridgeType {
                 ram: true,
                 battery: true,
             },
-            _ => panic!("Cartridge type unsupported"),
+            0x19 => CartridgeType::MBC5 {
+                rumble: false,
+                ram: false,
+                battery: false,
+            },
+            0x1A => CartridgeType::MBC5 {
+                rumble: false,
+                ram: true,
+                battery: false,
+            },
+            0x1B => CartridgeType::MBC5 {
+                rumble: false,
+                ram: true,
+                battery: true,
+            },
+            0x1C => CartridgeType::MBC5 {
+                rumble: true,
+                ram: false,
+                battery: false,
+            },
+            0x1D => CartridgeType::MBC5 {
+                rumble: true,
+                ram: true,
+                battery: false,
+            },
+            0x1E => CartridgeType::MBC5 {
+                rumble: true,
+                ram: true,
+                battery: true,
+            },
+            _ => return Err(CartridgeError::UnknownMapper(code)),
+        };
+        Ok(cartridge_type)
+    }
+
+    pub fn mapper(self) -> Mapper {
+        match self {
+            CartridgeType::MBCNone { .. } => Mapper::None,
+            CartridgeType::MBC1 { .. } => Mapper::Mbc1,
+            CartridgeType::MBC2 { .. } => Mapper::Mbc2,
+            CartridgeType::MMM01 { .. } => Mapper::Mmm01,
+            CartridgeType::MBC3 { .. } => Mapper::Mbc3,
+            CartridgeType::MBC5 { .. } => Mapper::Mbc5,
+        }
+    }
+
+    pub fn ram(self) -> bool {
+        match self {
+            CartridgeType::MBCNone { ram, .. }
+            | CartridgeType::MBC1 { ram, .. }
+            | CartridgeType::MMM01 { ram, .. }
+            | CartridgeType::MBC3 { ram, .. }
+            | CartridgeType::MBC5 { ram, .. } => ram,
+            CartridgeType::MBC2 { .. } => true,
+        }
+    }
+
+    pub fn battery(self) -> bool {
+        match self {
+            CartridgeType::MBCNone { battery, .. }
+            | CartridgeType::MBC1 { battery, .. }
+            | CartridgeType::MBC2 { battery }
+            | CartridgeType::MMM01 { battery, .. }
+            | CartridgeType::MBC3 { battery, .. }
+            | CartridgeType::MBC5 { battery, .. } => battery,
         }
     }
 }
@@ -155,18 +499,36 @@ enum RomSize {
 }
 
 impl RomSize {
-    pub fn new(code: u8) -> RomSize {
+    pub fn new(code: u8) -> Result<RomSize, CartridgeError> {
         match code {
-            0x00 => RomSize::KB32,
-            0x01 => RomSize::KB64,
-            0x02 => RomSize::KB128,
-            0x03 => RomSize::KB256,
-            0x04 => RomSize::KB512,
-            0x05 => RomSize::KB1024,
-            0x06 => RomSize::KB2048,
-            0x07 => RomSize::KB4096,
-            0x08 => RomSize::KB8192,
-            _ => panic!("Rom size not supported"),
+            0x00 => Ok(RomSize::KB32),
+            0x01 => Ok(RomSize::KB64),
+            0x02 => Ok(RomSize::KB128),
+            0x03 => Ok(RomSize::KB256),
+            0x04 => Ok(RomSize::KB512),
+            0x05 => Ok(RomSize::KB1024),
+            0x06 => Ok(RomSize::KB2048),
+            0x07 => Ok(RomSize::KB4096),
+            0x08 => Ok(RomSize::KB8192),
+            _ => Err(CartridgeError::InvalidRomSize(code)),
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        self.banks() * ROM_BANK_SIZE
+    }
+
+    pub fn banks(self) -> usize {
+        match self {
+            RomSize::KB32 => 2,
+            RomSize::KB64 => 4,
+            RomSize::KB128 => 8,
+            RomSize::KB256 => 16,
+            RomSize::KB512 => 32,
+            RomSize::KB1024 => 64,
+            RomSize::KB2048 => 128,
+            RomSize::KB4096 => 256,
+            RomSize::KB8192 => 512,
         }
     }
 }
@@ -182,31 +544,166 @@ enum RamSize {
 }
 
 impl RamSize {
-    pub fn new(code: u8) -> RamSize {
+    pub fn new(code: u8) -> Result<RamSize, CartridgeError> {
         match code {
-            0x00 => RamSize::None,
-            0x01 => RamSize::KB2,
-            0x02 => RamSize::KB8,
-            0x03 => RamSize::KB32,
-            0x04 => RamSize::KB128,
-            0x05 => RamSize::KB64,
-            _ => panic!("Ram size not supported"),
+            0x00 => Ok(RamSize::None),
+            0x01 => Ok(RamSize::KB2),
+            0x02 => Ok(RamSize::KB8),
+            0x03 => Ok(RamSize::KB32),
+            0x04 => Ok(RamSize::KB128),
+            0x05 => Ok(RamSize::KB64),
+            _ => Err(CartridgeError::InvalidRamSize(code)),
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            RamSize::None => 0,
+            RamSize::KB2 => 0x800,
+            RamSize::KB8 => 0x2000,
+            RamSize::KB32 => 0x8000,
+            RamSize::KB64 => 0x10000,
+            RamSize::KB128 => 0x20000,
+        }
+    }
+
+    pub fn banks(self) -> usize {
+        match self {
+            RamSize::None => 0,
+            RamSize::KB2 | RamSize::KB8 => 1,
+            RamSize::KB32 => 4,
+            RamSize::KB64 => 8,
+            RamSize::KB128 => 16,
         }
     }
 }
 
+/// State of a memory bank controller beyond its memory map.
+trait Mbc: MapsMemory + SaveState + Send {
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    /// The external RAM, followed by the RTC state for controllers with a clock.
+    fn save_ram(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_ram(&mut self, _data: &[u8]) {}
+}
+
 struct MemoryBankController {}
 
 impl MemoryBankController {
-    pub fn create_rom_memory(rom: &Rom) -> Box<dyn MapsMemory + Send> {
+    pub fn create_rom_memory(rom: &Rom, clock: Box<dyn Clock>) -> Box<dyn Mbc> {
         let rom_size = rom.header.rom_size;
         let ram_size = rom.header.ram_size;
         let rom_ref = &rom;
         match rom_ref.header.cartridge_type {
             CartridgeType::MBCNone { ram, .. } => MbcNone::new(&rom, ram),
-            CartridgeType::MBC1 { ram, .. } => Mbc1::new(&rom, ram, rom_size, ram_size),
-            _ => unimplemented!(),
+            CartridgeType::MBC1 { ram, .. } => Mbc1::new(rom, ram, rom_size, ram_size),
+            CartridgeType::MBC2 { .. } => Mbc2::new(rom, rom_size),
+            CartridgeType::MMM01 { ram, .. } => Mmm01::new(rom, ram, rom_size, ram_size),
+            CartridgeType::MBC3 { timer, ram, .. } => {
+                let clock = if timer { Some(clock) } else { None };
+                Mbc3::new(rom, ram, rom_size, ram_size, clock)
+            }
+            CartridgeType::MBC5 { rumble, ram, .. } => {
+                Mbc5::new(rom, ram, rumble, rom_size, ram_size)
+            }
+        }
+    }
+}
+
+/// Splits the ROM into 16 KB banks which are all mapped to 0x4000-0x7FFF, so every bank can be
+/// switched into either ROM region. Missing data reads as 0.
+fn create_rom_banks(data: &[u8], banks: usize) -> Vec<Memory> {
+    (0..banks)
+        .map(|bank| {
+            let start = (bank * ROM_BANK_SIZE).min(data.len());
+            let end = (start + ROM_BANK_SIZE).min(data.len());
+            Memory::new_read_only(&data[start..end], 0x4000, 0x7FFF)
+        })
+        .collect()
+}
+
+/// The external RAM of a cartridge, split into 8 KB banks that are mapped to 0xA000-0xBFFF.
+struct RamBanks {
+    banks: Vec<Memory>,
+    size: usize,
+}
+
+impl RamBanks {
+    fn new(ram_size: RamSize) -> RamBanks {
+        let banks = (0..ram_size.banks())
+            .map(|_| Memory::new_read_write(&[0u8; 0], 0xA000, 0xA000 + RAM_BANK_SIZE - 1))
+            .collect();
+        RamBanks {
+            banks,
+            size: ram_size.bytes(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.banks.is_empty()
+    }
+
+    /// RAM smaller than a bank repeats across the whole area.
+    fn address(&self, address: u16) -> u16 {
+        let bank_size = self.size.min(RAM_BANK_SIZE as usize) as u16;
+        0xA000 + (address - 0xA000) % bank_size
+    }
+
+    fn read(&self, bank: usize, address: u16) -> Result<u8, ()> {
+        if self.is_empty() {
+            return Ok(0xFF);
+        }
+        self.banks[bank % self.banks.len()].read(self.address(address))
+    }
+
+    fn write(&mut self, bank: usize, address: u16, value: u8) -> Result<(), ()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let address = self.address(address);
+        let bank = bank % self.banks.len();
+        self.banks[bank].write(address, value)
+    }
+
+    /// The banks in order, in the layout of a `.sav` file.
+    fn save(&self) -> Vec<u8> {
+        (0..self.size)
+            .map(|offset| self.read_offset(offset))
+            .collect()
+    }
+
+    fn load(&mut self, data: &[u8]) {
+        for (offset, &value) in data.iter().take(self.size).enumerate() {
+            let bank = offset / RAM_BANK_SIZE as usize;
+            let address = 0xA000 + (offset % RAM_BANK_SIZE as usize) as u16;
+            self.write(bank, address, value).unwrap();
+        }
+    }
+
+    fn read_offset(&self, offset: usize) -> u8 {
+        let bank = offset / RAM_BANK_SIZE as usize;
+        let address = 0xA000 + (offset % RAM_BANK_SIZE as usize) as u16;
+        self.read(bank, address).unwrap()
+    }
+}
+
+impl SaveState for RamBanks {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for bank in &self.banks {
+            bank.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for bank in &mut self.banks {
+            bank.load_state(reader)?;
         }
+        Ok(())
     }
 }
 
@@ -234,6 +731,17 @@ impl MbcNone {
     }
 }
 
+impl Mbc for MbcNone {}
+
+/// Without a controller the RAM can't be written, so there is nothing to save.
+impl SaveState for MbcNone {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
 impl MapsMemory for MbcNone {
     fn read(&self, address: u16) -> Result<u8, ()> {
         self.memory
@@ -254,160 +762,979 @@ impl MapsMemory for MbcNone {
 
 struct Mbc1 {
     rom: Vec<Memory>,
-    ram: Vec<Memory>,
-    ram_enable: u8,
+    ram: RamBanks,
+    multicart: bool,
+    ram_enable: bool,
     rom_bank_number: u8,
-    ram_bank_number: u8,
+    secondary_bank_number: u8,
     mode_select: u8,
 }
 
 impl Mbc1 {
     pub fn new(rom: &Rom, ram: bool, rom_size: RomSize, ram_size: RamSize) -> Box<Mbc1> {
-        let mut memory_rom = Vec::new();
-        let mut memory_ram = Vec::new();
-        let rom_banks = match rom_size {
-            RomSize::KB32 => 1,
-            RomSize::KB64 => 4,
-            RomSize::KB128 => 8,
-            RomSize::KB256 => 16,
-            RomSize::KB512 => 32,
-            RomSize::KB1024 => 63,
-            RomSize::KB2048 => 125,
-            _ => unreachable!(),
-        };
-        let end = if rom.data.len() >= 0x3FFF {
-            0x3FFF
+        let ram_size = if ram { ram_size } else { RamSize::None };
+        Box::new(Mbc1 {
+            rom: create_rom_banks(&rom.data, rom_size.banks()),
+            ram: RamBanks::new(ram_size),
+            multicart: Self::is_multicart(&rom.data),
+            ram_enable: false,
+            rom_bank_number: 1,
+            secondary_bank_number: 0,
+            mode_select: 0,
+        })
+    }
+
+    /// MBC1M multicarts wire the secondary register to ROM bank bits 4-5 instead of 5-6. They are
+    /// 1 MB large and every game starts with its own header at a multiple of 256 KB.
+    fn is_multicart(data: &[u8]) -> bool {
+        if data.len() != 64 * ROM_BANK_SIZE {
+            return false;
+        }
+        let games = (0..4)
+            .map(|game| game * 0x10 * ROM_BANK_SIZE + LOGO_START)
+            .filter(|&start| data[start..start + NINTENDO_LOGO.len()] == NINTENDO_LOGO)
+            .count();
+        games > 1
+    }
+
+    fn secondary_bank_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn rom_bank(&self, upper: bool) -> usize {
+        let secondary = if upper || self.mode_select == 1 {
+            self.secondary_bank_number << self.secondary_bank_shift()
         } else {
-            rom.data.len() - 1
+            0
         };
-        let bank0 = Memory::new_read_only(&rom.data[0x0000..=end], 0x0000, 0x3FFF);
-        memory_rom.push(bank0);
-        for i in 1..=rom_banks {
-            let start = if rom.data.len() >= 0x4000 * i {
-                0x4000 * i
-            } else {
-                rom.data.len()
-            };
-            let end = if rom.data.len() >= start + 0x3FFF {
-                start + 0x3FFF
-            } else {
-                rom.data.len() - 1
-            };
-            let banki = Memory::new_read_only(&rom.data[start..=end], 0x4000, 0x7FFF);
-            memory_rom.push(banki);
-        }
-        let (ram_banks, bank_size) = if ram {
-            match ram_size {
-                RamSize::None => (0, 0),
-                RamSize::KB2 => (1, 0x800),
-                RamSize::KB8 => (1, 0x2000),
-                RamSize::KB32 => (4, 0x2000),
-                _ => unreachable!(),
-            }
+        let bank = if upper {
+            let mask = (1 << self.secondary_bank_shift()) - 1;
+            secondary | (self.rom_bank_number & mask)
         } else {
-            (0, 0)
+            secondary
         };
-        for _ in 0..ram_banks {
-            memory_ram.push(Memory::new_read_write(
-                &[0u8; 0],
-                0xA000,
-                0xA000 + bank_size,
-            ));
+        bank as usize % self.rom.len()
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode_select == 1 {
+            self.secondary_bank_number as usize
+        } else {
+            0
         }
-        Box::new(Mbc1 {
-            rom: memory_rom,
-            ram: memory_ram,
-            ram_enable: 0,
-            rom_bank_number: 1,
-            ram_bank_number: 0,
-            mode_select: 0,
-        })
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn save_ram(&self) -> Vec<u8> {
+        self.ram.save()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        self.ram.load(data);
     }
 }
 
 impl MapsMemory for Mbc1 {
     fn read(&self, address: u16) -> Result<u8, ()> {
         match address {
-            0x0000..=0x3FFF => self.rom[0].read(address),
-            0x4000..=0x7FFF => {
-                let mut bank = self.rom_bank_number;
-                if self.mode_select == 0 {
-                    bank |= self.ram_bank_number << 5;
-                }
-                self.rom[bank as usize].read(address)
-            }
+            0x0000..=0x3FFF => self.rom[self.rom_bank(false)].read(address + 0x4000),
+            0x4000..=0x7FFF => self.rom[self.rom_bank(true)].read(address),
             0xA000..=0xBFFF => {
-                if (self.ram_enable & 0x0A) == 0x0A {
-                    let bank = if self.mode_select == 1 {
-                        self.ram_bank_number
-                    } else {
-                        0
-                    };
-                    self.ram[bank as usize].read(address)
+                if self.ram_enable {
+                    self.ram.read(self.ram_bank(), address)
                 } else {
-                    Err(())
+                    Ok(0xFF)
                 }
             }
-            _ => unreachable!(),
+            _ => Err(()),
         }
     }
 
     fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
         match address {
-            0x0000..=0x1FFF => {
-                self.ram_enable = value;
-                Ok(())
-            }
+            0x0000..=0x1FFF => self.ram_enable = value & 0x0F == 0x0A,
             0x2000..=0x3FFF => {
+                // Only the full five bits are checked for 0, so 0x20, 0x40 and 0x60 can't be
+                // mapped to 0x4000-0x7FFF
                 self.rom_bank_number = value & 0b11111;
                 if self.rom_bank_number == 0 {
-                    self.rom_bank_number = 1
-                };
-                Ok(())
-            }
-            0x4000..=0x5FFF => {
-                self.ram_bank_number = value & 0b11;
-                Ok(())
-            }
-            0x6000..=0x7FFF => {
-                self.mode_select = value & 0b1;
-                Ok(())
+                    self.rom_bank_number = 1;
+                }
             }
-            _ => {
-                let bank = self.ram_bank_number;
-                self.ram[bank as usize].write(address, value)
+            0x4000..=0x5FFF => self.secondary_bank_number = value & 0b11,
+            0x6000..=0x7FFF => self.mode_select = value & 0b1,
+            0xA000..=0xBFFF => {
+                if self.ram_enable {
+                    let bank = self.ram_bank();
+                    self.ram.write(bank, address, value)?;
+                }
             }
+            _ => return Err(()),
         }
+        Ok(())
     }
 
     fn is_in_range(&self, address: u16) -> bool {
-        let rom = self.rom.iter().any(|mem| mem.is_in_range(address));
-        let ram = self.ram.iter().any(|mem| mem.is_in_range(address));
-        ram || rom
+        matches!(address, 0x0000..=0x7FFF | 0xA000..=0xBFFF)
     }
 }
 
-pub struct Cartridge {
-    mbc: Box<dyn MapsMemory + Send>,
-    header: CartridgeHeader,
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram.save_state(writer);
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank_number);
+        writer.write_u8(self.secondary_bank_number);
+        writer.write_u8(self.mode_select);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram.load_state(reader)?;
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank_number = reader.read_u8()?;
+        self.secondary_bank_number = reader.read_u8()?;
+        self.mode_select = reader.read_u8()?;
+        Ok(())
+    }
 }
 
-impl Cartridge {
-    pub fn new(game: Vec<u8>) -> Cartridge {
-        let mut data = Vec::new();
-        for val in game {
-            data.push(val);
+struct Mbc2 {
+    rom: Vec<Memory>,
+    ram: Memory,
+    ram_enable: bool,
+    rom_bank_number: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: &Rom, rom_size: RomSize) -> Box<Mbc2> {
+        // MBC2 only has four ROM bank bits
+        let banks = rom_size.banks().min(16);
+        Box::new(Mbc2 {
+            rom: create_rom_banks(&rom.data, banks),
+            ram: Memory::new_read_write(&[0u8; 0], 0xA000, 0xA1FF),
+            ram_enable: false,
+            rom_bank_number: 1,
+        })
+    }
+
+    /// The 512 half-bytes of RAM repeat across the whole external RAM area.
+    fn ram_address(address: u16) -> u16 {
+        0xA000 + (address & 0x01FF)
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn save_ram(&self) -> Vec<u8> {
+        (0xA000..=0xA1FF)
+            .map(|address| self.ram.read(address).unwrap())
+            .collect()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        for (address, &value) in (0xA000..=0xA1FF).zip(data) {
+            self.ram.write(address, value & 0x0F).unwrap();
         }
-        let header = CartridgeHeader::new(&data);
-        let mbc = MemoryBankController::create_rom_memory(&Rom {
-            data,
-            header: header.clone(),
-        });
-        Cartridge { mbc, header }
     }
+}
 
-    pub fn title(&self) -> &str {
-        &self.header.title
+impl MapsMemory for Mbc2 {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            0x0000..=0x3FFF => self.rom[0].read(address + 0x4000),
+            0x4000..=0x7FFF => {
+                let bank = self.rom_bank_number as usize % self.rom.len();
+                self.rom[bank].read(address)
+            }
+            0xA000..=0xBFFF => {
+                if self.ram_enable {
+                    let value = self.ram.read(Self::ram_address(address))?;
+                    Ok(0xF0 | value)
+                } else {
+                    Ok(0xFF)
+                }
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            // Bit 8 of the address selects between RAMG and ROMB
+            0x0000..=0x3FFF => {
+                if (address >> 8) & 1 == 0 {
+                    self.ram_enable = value & 0x0F == 0x0A;
+                } else {
+                    self.rom_bank_number = value & 0x0F;
+                    if self.rom_bank_number == 0 {
+                        self.rom_bank_number = 1;
+                    }
+                }
+                Ok(())
+            }
+            0x4000..=0x7FFF => Ok(()),
+            0xA000..=0xBFFF => {
+                if self.ram_enable {
+                    self.ram.write(Self::ram_address(address), value & 0x0F)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        matches!(address, 0x0000..=0x7FFF | 0xA000..=0xBFFF)
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram.save_state(writer);
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank_number);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram.load_state(reader)?;
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank_number = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+/// MMM01 multicarts start unmapped with the menu in the last 32 KB of the ROM. The menu then
+/// selects the outer banks of a game and locks them, after which the game sees an MBC1.
+struct Mmm01 {
+    rom: Vec<Memory>,
+    ram: RamBanks,
+    mapped: bool,
+    ram_enable: bool,
+    rom_bank_low: u8,
+    rom_bank_mid: u8,
+    rom_bank_high: u8,
+    rom_bank_mask: u8,
+    ram_bank_low: u8,
+    ram_bank_high: u8,
+    ram_bank_mask: u8,
+    mode_select: u8,
+    mode_protect: bool,
+}
+
+impl Mmm01 {
+    pub fn new(rom: &Rom, ram: bool, rom_size: RomSize, ram_size: RamSize) -> Box<Mmm01> {
+        let ram_size = if ram { ram_size } else { RamSize::None };
+        let banks = rom_size.banks().max(rom.data.len() / ROM_BANK_SIZE);
+        Box::new(Mmm01 {
+            rom: create_rom_banks(&rom.data, banks),
+            ram: RamBanks::new(ram_size),
+            mapped: false,
+            ram_enable: false,
+            rom_bank_low: 0,
+            rom_bank_mid: 0,
+            rom_bank_high: 0,
+            rom_bank_mask: 0,
+            ram_bank_low: 0,
+            ram_bank_high: 0,
+            ram_bank_mask: 0,
+            mode_select: 0,
+            mode_protect: false,
+        })
+    }
+
+    fn outer_rom_bank(&self) -> usize {
+        (usize::from(self.rom_bank_high) << 7) | (usize::from(self.rom_bank_mid) << 5)
+    }
+
+    fn rom_bank(&self, upper: bool) -> usize {
+        let bank = if !self.mapped {
+            // Unmapped, every bank line is pulled high so the last 32 KB are visible
+            0x1FE | upper as usize
+        } else if upper {
+            let low = if self.rom_bank_low == 0 {
+                1
+            } else {
+                self.rom_bank_low
+            };
+            self.outer_rom_bank() | usize::from(low)
+        } else {
+            self.outer_rom_bank() | usize::from(self.rom_bank_low & (self.rom_bank_mask << 1))
+        };
+        bank % self.rom.len()
+    }
+
+    fn ram_bank(&self) -> usize {
+        let low = if self.mode_select == 1 {
+            self.ram_bank_low
+        } else {
+            self.ram_bank_low & self.ram_bank_mask
+        };
+        usize::from((self.ram_bank_high << 2) | low)
+    }
+
+    /// Once mapped, bits selected by a mask keep the value the menu left them at.
+    fn masked(old: u8, new: u8, mask: u8) -> u8 {
+        (old & mask) | (new & !mask)
+    }
+}
+
+impl Mbc for Mmm01 {
+    fn save_ram(&self) -> Vec<u8> {
+        self.ram.save()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        self.ram.load(data);
+    }
+}
+
+impl SaveState for Mmm01 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram.save_state(writer);
+        writer.write_bool(self.mapped);
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank_low);
+        writer.write_u8(self.rom_bank_mid);
+        writer.write_u8(self.rom_bank_high);
+        writer.write_u8(self.rom_bank_mask);
+        writer.write_u8(self.ram_bank_low);
+        writer.write_u8(self.ram_bank_high);
+        writer.write_u8(self.ram_bank_mask);
+        writer.write_u8(self.mode_select);
+        writer.write_bool(self.mode_protect);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram.load_state(reader)?;
+        self.mapped = reader.read_bool()?;
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank_low = reader.read_u8()?;
+        self.rom_bank_mid = reader.read_u8()?;
+        self.rom_bank_high = reader.read_u8()?;
+        self.rom_bank_mask = reader.read_u8()?;
+        self.ram_bank_low = reader.read_u8()?;
+        self.ram_bank_high = reader.read_u8()?;
+        self.ram_bank_mask = reader.read_u8()?;
+        self.mode_select = reader.read_u8()?;
+        self.mode_protect = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl MapsMemory for Mmm01 {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            0x0000..=0x3FFF => self.rom[self.rom_bank(false)].read(address + 0x4000),
+            0x4000..=0x7FFF => self.rom[self.rom_bank(true)].read(address),
+            0xA000..=0xBFFF => {
+                if self.ram_enable {
+                    self.ram.read(self.ram_bank(), address)
+                } else {
+                    Ok(0xFF)
+                }
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_enable = value & 0x0F == 0x0A;
+                if !self.mapped {
+                    self.ram_bank_mask = (value >> 4) & 0b11;
+                    self.mapped = (value >> 6) & 1 == 1;
+                }
+            }
+            0x2000..=0x3FFF => {
+                if self.mapped {
+                    let mask = self.rom_bank_mask << 1;
+                    self.rom_bank_low = Self::masked(self.rom_bank_low, value & 0x1F, mask);
+                } else {
+                    self.rom_bank_low = value & 0x1F;
+                    self.rom_bank_mid = (value >> 5) & 0b11;
+                }
+            }
+            0x4000..=0x5FFF => {
+                if self.mapped {
+                    let mask = self.ram_bank_mask;
+                    self.ram_bank_low = Self::masked(self.ram_bank_low, value & 0b11, mask);
+                } else {
+                    self.ram_bank_low = value & 0b11;
+                    self.ram_bank_high = (value >> 2) & 0b11;
+                    self.rom_bank_high = (value >> 4) & 0b11;
+                    self.mode_protect = (value >> 6) & 1 == 1;
+                }
+            }
+            0x6000..=0x7FFF => {
+                if !self.mode_protect {
+                    self.mode_select = value & 1;
+                }
+                if !self.mapped {
+                    self.rom_bank_mask = (value >> 2) & 0x0F;
+                }
+            }
+            0xA000..=0xBFFF => {
+                if self.ram_enable {
+                    let bank = self.ram_bank();
+                    self.ram.write(bank, address, value)?;
+                }
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        matches!(address, 0x0000..=0x7FFF | 0xA000..=0xBFFF)
+    }
+}
+
+/// The time source of the MBC3 real-time clock.
+pub trait Clock: Send {
+    /// Seconds elapsed since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    day_carry: bool,
+}
+
+impl RtcRegisters {
+    fn advance(&mut self, seconds: u64) {
+        let total = u64::from(self.seconds) + seconds;
+        self.seconds = (total % 60) as u8;
+        let total = u64::from(self.minutes) + total / 60;
+        self.minutes = (total % 60) as u8;
+        let total = u64::from(self.hours) + total / 60;
+        self.hours = (total % 24) as u8;
+        let days = u64::from(self.days) + total / 24;
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds & 0x3F,
+            0x09 => self.minutes & 0x3F,
+            0x0A => self.hours & 0x1F,
+            0x0B => self.days as u8,
+            0x0C => {
+                ((self.day_carry as u8) << 7) | ((self.halt as u8) << 6) | (self.days >> 8) as u8
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | u16::from(value),
+            0x0C => {
+                self.days = (self.days & 0xFF) | (u16::from(value & 1) << 8);
+                self.halt = (value >> 6) & 1 == 1;
+                self.day_carry = (value >> 7) & 1 == 1;
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl SaveState for RtcRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.seconds);
+        writer.write_u8(self.minutes);
+        writer.write_u8(self.hours);
+        writer.write_u16(self.days);
+        writer.write_bool(self.halt);
+        writer.write_bool(self.day_carry);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = reader.read_u8()?;
+        self.minutes = reader.read_u8()?;
+        self.hours = reader.read_u8()?;
+        self.days = reader.read_u16()? & 0x1FF;
+        self.halt = reader.read_bool()?;
+        self.day_carry = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// The MBC3 clock counts in the live registers while the CPU only sees the latched copy.
+struct RealTimeClock {
+    clock: Box<dyn Clock>,
+    live: RtcRegisters,
+    latched: RtcRegisters,
+    last_update: u64,
+    latch_armed: bool,
+}
+
+impl RealTimeClock {
+    fn new(clock: Box<dyn Clock>) -> RealTimeClock {
+        let last_update = clock.now();
+        RealTimeClock {
+            clock,
+            live: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            last_update,
+            latch_armed: false,
+        }
+    }
+
+    fn update(&mut self) {
+        let now = self.clock.now();
+        if !self.live.halt {
+            self.live.advance(now.saturating_sub(self.last_update));
+        }
+        self.last_update = now;
+    }
+
+    /// Writing 0x00 and then 0x01 copies the live registers into the latched ones.
+    fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.live;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        self.latched.read(register)
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let now = self.clock.now();
+        let mut live = self.live;
+        if !live.halt {
+            live.advance(now.saturating_sub(self.last_update));
+        }
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for registers in &[live, self.latched] {
+            for register in 0x08..=0x0C {
+                footer.extend_from_slice(&u32::from(registers.read(register)).to_le_bytes());
+            }
+        }
+        footer.extend_from_slice(&now.to_le_bytes());
+        footer
+    }
+
+    /// The time that passed since the footer was written is added on the next update.
+    fn load(&mut self, footer: &[u8]) {
+        for register in 0x08..=0x0C {
+            let index = usize::from(register - 0x08) * 4;
+            self.live.write(register, footer[index]);
+            self.latched.write(register, footer[20 + index]);
+        }
+        self.last_update = if footer.len() == RTC_FOOTER_SIZE {
+            u64::from_le_bytes(footer[40..48].try_into().unwrap())
+        } else {
+            u64::from(u32::from_le_bytes(footer[40..44].try_into().unwrap()))
+        };
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        self.live.write(register, value);
+        self.latched.write(register, value);
+    }
+}
+
+/// The clock source is not saved; time that passed since the state was written is added on the
+/// next update, like with the `.sav` footer.
+impl SaveState for RealTimeClock {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.live.save_state(writer);
+        self.latched.save_state(writer);
+        writer.write_u64(self.last_update);
+        writer.write_bool(self.latch_armed);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.live.load_state(reader)?;
+        self.latched.load_state(reader)?;
+        self.last_update = reader.read_u64()?;
+        self.latch_armed = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+struct Mbc3 {
+    rom: Vec<Memory>,
+    ram: RamBanks,
+    rtc: Option<RealTimeClock>,
+    ram_enable: bool,
+    rom_bank_number: u8,
+    ram_bank_number: u8,
+}
+
+impl Mbc3 {
+    pub fn new(
+        rom: &Rom,
+        ram: bool,
+        rom_size: RomSize,
+        ram_size: RamSize,
+        clock: Option<Box<dyn Clock>>,
+    ) -> Box<Mbc3> {
+        let ram_size = if ram { ram_size } else { RamSize::None };
+        Box::new(Mbc3 {
+            rom: create_rom_banks(&rom.data, rom_size.banks().min(128)),
+            ram: RamBanks::new(ram_size),
+            rtc: clock.map(RealTimeClock::new),
+            ram_enable: false,
+            rom_bank_number: 1,
+            ram_bank_number: 0,
+        })
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn save_ram(&self) -> Vec<u8> {
+        let mut data = self.ram.save();
+        if let Some(rtc) = &self.rtc {
+            data.extend(rtc.save());
+        }
+        data
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        self.ram.load(data);
+        if let Some(rtc) = &mut self.rtc {
+            let footer = &data[self.ram.size.min(data.len())..];
+            if footer.len() == RTC_FOOTER_SIZE || footer.len() == RTC_FOOTER_SIZE_32BIT_TIMESTAMP {
+                rtc.load(footer);
+            }
+        }
+    }
+}
+
+impl MapsMemory for Mbc3 {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            0x0000..=0x3FFF => self.rom[0].read(address + 0x4000),
+            0x4000..=0x7FFF => {
+                let bank = self.rom_bank_number as usize % self.rom.len();
+                self.rom[bank].read(address)
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enable {
+                    return Ok(0xFF);
+                }
+                match (self.ram_bank_number, &self.rtc) {
+                    (0x00..=0x03, _) => self.ram.read(self.ram_bank_number as usize, address),
+                    (0x08..=0x0C, Some(rtc)) => Ok(rtc.read(self.ram_bank_number)),
+                    _ => Ok(0xFF),
+                }
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank_number = value & 0x7F;
+                if self.rom_bank_number == 0 {
+                    self.rom_bank_number = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank_number = value,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+            0xA000..=0xBFFF => {
+                if !self.ram_enable {
+                    return Ok(());
+                }
+                match (self.ram_bank_number, &mut self.rtc) {
+                    (0x00..=0x03, _) => {
+                        let bank = self.ram_bank_number as usize;
+                        self.ram.write(bank, address, value)?;
+                    }
+                    (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank_number, value),
+                    _ => {}
+                }
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        matches!(address, 0x0000..=0x7FFF | 0xA000..=0xBFFF)
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram.save_state(writer);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+        writer.write_bool(self.ram_enable);
+        writer.write_u8(self.rom_bank_number);
+        writer.write_u8(self.ram_bank_number);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram.load_state(reader)?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(reader)?;
+        }
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank_number = reader.read_u8()?;
+        self.ram_bank_number = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+struct Mbc5 {
+    rom: Vec<Memory>,
+    ram: RamBanks,
+    has_rumble: bool,
+    rumble: bool,
+    ram_enable: bool,
+    rom_bank_number: u16,
+    ram_bank_number: u8,
+}
+
+impl Mbc5 {
+    pub fn new(
+        rom: &Rom,
+        ram: bool,
+        rumble: bool,
+        rom_size: RomSize,
+        ram_size: RamSize,
+    ) -> Box<Mbc5> {
+        let ram_size = if ram { ram_size } else { RamSize::None };
+        Box::new(Mbc5 {
+            rom: create_rom_banks(&rom.data, rom_size.banks()),
+            ram: RamBanks::new(ram_size),
+            has_rumble: rumble,
+            rumble: false,
+            ram_enable: false,
+            rom_bank_number: 1,
+            ram_bank_number: 0,
+        })
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    fn save_ram(&self) -> Vec<u8> {
+        self.ram.save()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        self.ram.load(data);
+    }
+}
+
+impl MapsMemory for Mbc5 {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            0x0000..=0x3FFF => self.rom[0].read(address + 0x4000),
+            0x4000..=0x7FFF => {
+                let bank = self.rom_bank_number as usize % self.rom.len();
+                self.rom[bank].read(address)
+            }
+            0xA000..=0xBFFF => {
+                if self.ram_enable {
+                    self.ram.read(self.ram_bank_number as usize, address)
+                } else {
+                    Ok(0xFF)
+                }
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = value == 0x0A,
+            // Unlike the older controllers bank 0 can be mapped to 0x4000-0x7FFF
+            0x2000..=0x2FFF => {
+                self.rom_bank_number = (self.rom_bank_number & 0x100) | u16::from(value);
+            }
+            0x3000..=0x3FFF => {
+                self.rom_bank_number = (self.rom_bank_number & 0xFF) | (u16::from(value & 1) << 8);
+            }
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    // The rumble motor is wired to bit 3 instead of the RAM bank lines
+                    self.rumble = (value >> 3) & 1 == 1;
+                    self.ram_bank_number = value & 0x07;
+                } else {
+                    self.ram_bank_number = value & 0x0F;
+                }
+            }
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => {
+                if self.ram_enable {
+                    let bank = self.ram_bank_number as usize;
+                    self.ram.write(bank, address, value)?;
+                }
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        matches!(address, 0x0000..=0x7FFF | 0xA000..=0xBFFF)
+    }
+}
+
+impl SaveState for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.ram.save_state(writer);
+        writer.write_bool(self.rumble);
+        writer.write_bool(self.ram_enable);
+        writer.write_u16(self.rom_bank_number);
+        writer.write_u8(self.ram_bank_number);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.ram.load_state(reader)?;
+        self.rumble = reader.read_bool()? && self.has_rumble;
+        self.ram_enable = reader.read_bool()?;
+        self.rom_bank_number = reader.read_u16()? & 0x1FF;
+        self.ram_bank_number = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+pub struct Cartridge {
+    mbc: Box<dyn Mbc>,
+    header: CartridgeHeader,
+    rom_checksum: u32,
+}
+
+impl Cartridge {
+    /// Checks the header and its checksum before mapping the ROM. Like the hardware it accepts
+    /// a wrong global checksum, which is reported in `CartridgeInfo`.
+    pub fn new(game: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        Self::with_clock(game, Box::new(SystemClock))
+    }
+
+    /// Like `new`, but also rejects ROMs whose global checksum doesn't match.
+    pub fn new_strict(game: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let cartridge = Self::new(game)?;
+        let header = &cartridge.header;
+        if header.global_checksum != header.actual_global_checksum {
+            return Err(CartridgeError::GlobalChecksum {
+                expected: header.global_checksum,
+                actual: header.actual_global_checksum,
+            });
+        }
+        Ok(cartridge)
+    }
+
+    /// Creates a cartridge whose battery-backed RAM starts out with the contents of a `.sav` file.
+    pub fn with_ram(game: Vec<u8>, ram: &[u8]) -> Result<Cartridge, CartridgeError> {
+        let mut cartridge = Self::new(game)?;
+        cartridge.load_ram(ram);
+        Ok(cartridge)
+    }
+
+    /// Creates a cartridge whose real-time clock, if it has one, is driven by `clock`.
+    pub fn with_clock(game: Vec<u8>, clock: Box<dyn Clock>) -> Result<Cartridge, CartridgeError> {
+        let mut data = Vec::new();
+        for val in game {
+            data.push(val);
+        }
+        let header = CartridgeHeader::new(&data)?;
+        let rom_checksum = state::rom_checksum(&data);
+        let mbc = MemoryBankController::create_rom_memory(
+            &Rom {
+                data,
+                header: header.clone(),
+            },
+            clock,
+        );
+        Ok(Cartridge {
+            mbc,
+            header,
+            rom_checksum,
+        })
+    }
+
+    pub fn title(&self) -> &str {
+        &self.header.title
+    }
+
+    /// The whole title area 0x134-0x143 as stored in the header, without cutting it at a NUL.
+    pub(crate) fn title_bytes(&self) -> &[u8] {
+        &self.header.title_bytes
+    }
+
+    pub fn info(&self) -> CartridgeInfo {
+        self.header.info()
+    }
+
+    /// The battery-backed RAM in the `.sav` layout used by other emulators, including the RTC
+    /// footer for MBC3 cartridges with a clock. `None` if the cartridge has no battery.
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        if self.header.cartridge_type.battery() {
+            Some(self.mbc.save_ram())
+        } else {
+            None
+        }
+    }
+
+    pub fn load_ram(&mut self, data: &[u8]) {
+        self.mbc.load_ram(data);
+    }
+
+    /// Whether the rumble motor of the cartridge is currently turned on.
+    pub fn rumble(&self) -> bool {
+        self.mbc.rumble()
+    }
+
+    pub(crate) fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.mbc.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.mbc.load_state(reader)
     }
 }
 
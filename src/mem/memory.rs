@@ -1,3 +1,5 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct ReadOnly {
     memory: MemoryInternal,
@@ -33,6 +35,30 @@ impl Memory {
         };
         Memory::ReadWrite { memory }
     }
+
+    fn internal(&self) -> &MemoryInternal {
+        match self {
+            Memory::ReadOnly { memory } => &memory.memory,
+            Memory::ReadWrite { memory } => &memory.memory,
+        }
+    }
+
+    fn internal_mut(&mut self) -> &mut MemoryInternal {
+        match self {
+            Memory::ReadOnly { memory } => &mut memory.memory,
+            Memory::ReadWrite { memory } => &mut memory.memory,
+        }
+    }
+}
+
+impl SaveState for Memory {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.internal().memory);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.internal_mut().memory)
+    }
 }
 
 impl MapsMemory for Memory {
@@ -54,10 +80,7 @@ impl MapsMemory for Memory {
     }
 
     fn is_in_range(&self, address: u16) -> bool {
-        let memory = match self {
-            Memory::ReadOnly { memory } => &memory.memory,
-            Memory::ReadWrite { memory } => &memory.memory,
-        };
+        let memory = self.internal();
         memory.from <= address && address <= memory.to
     }
 }
@@ -1,2 +1,4 @@
 pub mod cartridge;
+mod licensee;
 pub mod memory;
+pub mod work_ram;
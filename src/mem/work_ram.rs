@@ -0,0 +1,111 @@
+use super::memory::{MapsMemory, Memory};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const WRAM_START: u16 = 0xC000;
+const SWITCHABLE_BANK_START: u16 = 0xD000;
+const ECHO_START: u16 = 0xE000;
+const ECHO_END: u16 = 0xFDFF;
+const SVBK_REGISTER: u16 = 0xFF70;
+
+const BANK_SIZE: u16 = 0x1000;
+const DMG_BANKS: usize = 2;
+const CGB_BANKS: usize = 8;
+
+/// Work RAM at 0xC000-0xDFFF and its echo at 0xE000-0xFDFF. In CGB mode SVBK selects which of
+/// seven banks is mapped to 0xD000-0xDFFF.
+pub(crate) struct WorkRam {
+    banks: Vec<Memory>,
+    bank_number: u8,
+    cgb_mode: bool,
+}
+
+impl WorkRam {
+    pub fn new(cgb_mode: bool) -> WorkRam {
+        let banks = if cgb_mode { CGB_BANKS } else { DMG_BANKS };
+        let banks = (0..banks)
+            .map(|bank| {
+                let start = if bank == 0 {
+                    WRAM_START
+                } else {
+                    SWITCHABLE_BANK_START
+                };
+                Memory::new_read_write(&[0u8; 0], start, start + BANK_SIZE - 1)
+            })
+            .collect();
+        WorkRam {
+            banks,
+            bank_number: 0,
+            cgb_mode,
+        }
+    }
+
+    /// Selecting bank 0 maps bank 1.
+    fn switchable_bank(&self) -> usize {
+        usize::from(self.bank_number.max(1))
+    }
+
+    /// The bank an address belongs to and the address inside it, with the echo resolved.
+    fn locate(&self, address: u16) -> (usize, u16) {
+        let address = if address >= ECHO_START {
+            address - (ECHO_START - WRAM_START)
+        } else {
+            address
+        };
+        if address < SWITCHABLE_BANK_START {
+            (0, address)
+        } else {
+            (self.switchable_bank(), address)
+        }
+    }
+}
+
+impl MapsMemory for WorkRam {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            SVBK_REGISTER if self.cgb_mode => Ok(0xF8 | self.bank_number),
+            SVBK_REGISTER => Ok(0xFF),
+            WRAM_START..=ECHO_END => {
+                let (bank, address) = self.locate(address);
+                self.banks[bank].read(address)
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            SVBK_REGISTER => {
+                if self.cgb_mode {
+                    self.bank_number = value & 0b111;
+                }
+                Ok(())
+            }
+            WRAM_START..=ECHO_END => {
+                let (bank, address) = self.locate(address);
+                self.banks[bank].write(address, value)
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        (WRAM_START..=ECHO_END).contains(&address) || address == SVBK_REGISTER
+    }
+}
+
+impl SaveState for WorkRam {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for bank in &self.banks {
+            bank.save_state(writer);
+        }
+        writer.write_u8(self.bank_number);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for bank in &mut self.banks {
+            bank.load_state(reader)?;
+        }
+        self.bank_number = reader.read_u8()? & 0b111;
+        Ok(())
+    }
+}
@@ -1,23 +1,76 @@
 use crate::{
-    gpu::{ppu::PixelProcessingUnit, screen::ScreenFetcher},
+    apu::audio::AudioProcessingUnit,
+    emulator::model::Model,
+    gpu::{
+        compatibility::{CompatibilityColors, CompatibilityPalette},
+        palette::Palette,
+        ppu::PixelProcessingUnit,
+        screen::ScreenFetcher,
+    },
+    input::joypad::{Button, Joypad},
     mem::{
-        cartridge::Cartridge,
+        cartridge::{Cartridge, CgbSupport},
         memory::{MapsMemory, Memory},
+        work_ram::WorkRam,
+    },
+    processor::{
+        dma::{OamDma, VramDma, TICKS_PER_BLOCK},
+        interrupt_controller::{
+            Interrupt, InterruptController, INTERRUPT_DISPATCH_TICKS, INTERRUPT_ENABLE_REGISTER,
+            INTERRUPT_FLAG_REGISTER,
+        },
+        opcodes,
+        registers::Registers,
+        serial::Serial,
+        speed::{SpeedSwitch, SPEED_SWITCH_TICKS},
+        timer::{Timer, DIVIDER_REGISTER},
     },
-    processor::{interrupt_controller::InterruptController, opcodes, registers::Registers},
+    state::{self, SaveState, StateError, StateReader, StateWriter},
+    util::memory_op,
 };
 use std::{cell::RefCell, rc::Rc};
 
+const LOW_POWER_TICKS: i64 = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum CpuState {
+    Running,
+    Halted,
+    Stopped,
+}
+
+impl CpuState {
+    fn from_bits(bits: u8) -> Option<CpuState> {
+        match bits {
+            0 => Some(CpuState::Running),
+            1 => Some(CpuState::Halted),
+            2 => Some(CpuState::Stopped),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) struct Cpu {
     pub registers: Registers,
     pub interrupt: InterruptController,
 
     memory: Vec<Memory>,
+    wram: WorkRam,
     io_registers: Memory,
     boot_rom: Option<Memory>,
+    boot_rom_mapped: bool,
     ppu: PixelProcessingUnit,
+    apu: AudioProcessingUnit,
+    timer: Timer,
+    serial: Serial,
+    speed: SpeedSwitch,
+    joypad: Joypad,
+    dma: OamDma,
+    hdma: VramDma,
     cartridge: Cartridge,
 
+    state: CpuState,
+    halt_bug: bool,
     cpu_wait_cycles: i64,
 }
 
@@ -27,55 +80,236 @@ impl Cpu {
         cartridge: Cartridge,
         lcd_fetcher: Rc<RefCell<ScreenFetcher>>,
         boot_rom: Option<Vec<u8>>,
+        model: Model,
     ) -> Cpu {
         let boot_rom =
             boot_rom.map(|opcodes| Memory::new_read_only(&opcodes, 0x0000, opcodes.len() as u16));
         let boot_sequence = boot_rom.is_some();
+        let cgb_mode = model == Model::Cgb && cartridge.info().cgb != CgbSupport::Unsupported;
         let memory = Self::init_memory();
+        let wram = WorkRam::new(cgb_mode);
         let io_registers = Memory::new_read_write(&[0u8; 0], 0xFF00, 0xFF7F);
-        let ppu = PixelProcessingUnit::new(lcd_fetcher);
+        let ppu = PixelProcessingUnit::new(lcd_fetcher, cgb_mode);
+        let apu = AudioProcessingUnit::new();
+        let timer = Timer::new();
+        let serial = Serial::new(cgb_mode);
+        let speed = SpeedSwitch::new(cgb_mode);
+        let joypad = Joypad::new();
+        let dma = OamDma::new();
+        let hdma = VramDma::new(cgb_mode);
         let cpu_wait_cycles = 0;
         let mut cpu = Cpu {
             registers: Registers::new(boot_sequence),
             interrupt,
             memory,
+            wram,
             io_registers,
             boot_rom,
+            boot_rom_mapped: boot_sequence,
             ppu,
+            apu,
+            timer,
+            serial,
+            speed,
+            joypad,
+            dma,
+            hdma,
             cartridge,
+            state: CpuState::Running,
+            halt_bug: false,
             cpu_wait_cycles,
         };
         cpu.init_boot_state(boot_sequence);
+        if !boot_sequence && model == Model::Cgb {
+            cpu.init_cgb_registers(cgb_mode);
+            if !cgb_mode {
+                let colors = CompatibilityColors::for_cartridge(&cpu.cartridge);
+                cpu.ppu.set_compatibility_palettes(colors);
+            }
+        }
         cpu
     }
 
+    /// Advances the machine by one T-cycle of the PPU and APU. In double speed mode the CPU,
+    /// timer, serial port and OAM DMA get two cycles in that time.
     pub fn step(&mut self) {
         {
             let io_registers = &mut self.io_registers;
             let interrupt = &mut self.interrupt;
-            self.ppu.step(io_registers, interrupt);
+            self.ppu.step(io_registers, interrupt, &mut self.hdma);
+        }
+        self.step_hdma();
+        self.step_timers();
+        self.apu.step(self.frame_sequencer_divider());
+        self.joypad.step(&mut self.interrupt);
+        self.step_cpu();
+        if self.speed.is_double_speed() {
+            self.step_timers();
+            self.step_cpu();
+        }
+    }
+
+    fn step_timers(&mut self) {
+        if self.state != CpuState::Stopped {
+            self.timer.step(&mut self.interrupt);
         }
+        self.serial.step(self.timer.divider(), &mut self.interrupt);
+    }
+
+    /// The frame sequencer uses a higher divider bit in double speed mode, so it keeps its rate.
+    fn frame_sequencer_divider(&self) -> u16 {
+        if self.speed.is_double_speed() {
+            self.timer.divider() >> 1
+        } else {
+            self.timer.divider()
+        }
+    }
+
+    fn step_cpu(&mut self) {
+        self.step_dma();
         if self.cpu_wait_cycles <= 0 {
-            let pc = self.registers.pc();
+            match self.state {
+                CpuState::Running => self.execute_next(),
+                CpuState::Halted => {
+                    if self.interrupt.pending().is_some() {
+                        self.state = CpuState::Running;
+                    }
+                    self.cpu_wait_cycles += LOW_POWER_TICKS;
+                }
+                CpuState::Stopped => {
+                    if self.joypad.take_falling_edge() {
+                        self.state = CpuState::Running;
+                    }
+                    self.cpu_wait_cycles += LOW_POWER_TICKS;
+                }
+            }
+        }
+        self.cpu_wait_cycles -= 1;
+    }
+
+    fn execute_next(&mut self) {
+        if let Some(interrupt) = self.next_interrupt() {
+            self.cpu_wait_cycles += i64::from(self.dispatch_interrupt(interrupt));
+        } else {
+            let mut pc = self.registers.pc();
             let opcode = self.read(pc).unwrap();
+            if self.halt_bug {
+                // The byte after HALT is fetched twice, so the instruction runs as if it started
+                // one byte earlier.
+                self.halt_bug = false;
+                pc = pc.wrapping_sub(1);
+                self.registers.set_pc(pc);
+            }
             //println!("pc: {:#06x} | opcode: {:#04x}", pc, opcode);
             self.cpu_wait_cycles += i64::from(opcodes::execute(opcode, pc, self));
+            self.interrupt.instruction_executed();
         }
-        self.cpu_wait_cycles -= 1;
+    }
+
+    fn step_dma(&mut self) {
+        if let Some((source, destination)) = self.dma.step() {
+            let value = self.read_bus(source).unwrap_or(0xFF);
+            self.dma.transferred(value);
+            self.ppu.write(destination, value).unwrap();
+        }
+    }
+
+    /// Copies the VRAM DMA blocks that are due, the CPU is stalled while they are copied.
+    fn step_hdma(&mut self) {
+        while let Some((source, destination)) = self.hdma.next_block() {
+            for offset in 0..0x10 {
+                let value = self.read_bus(source.wrapping_add(offset)).unwrap_or(0xFF);
+                self.ppu.write(destination + offset, value).unwrap();
+            }
+            let ticks = if self.speed.is_double_speed() {
+                2 * TICKS_PER_BLOCK
+            } else {
+                TICKS_PER_BLOCK
+            };
+            self.cpu_wait_cycles += ticks;
+        }
+    }
+
+    pub fn halt(&mut self) {
+        if !self.interrupt.master_enable && self.interrupt.pending().is_some() {
+            self.halt_bug = true;
+        } else {
+            self.state = CpuState::Halted;
+        }
+    }
+
+    /// STOP switches the CPU speed instead of stopping if a switch was requested through KEY1.
+    pub fn stop(&mut self) {
+        self.timer.write(DIVIDER_REGISTER, 0).unwrap();
+        if self.speed.switch() {
+            self.cpu_wait_cycles += SPEED_SWITCH_TICKS;
+        } else {
+            // Only a button pressed from now on ends STOP, not one that is already held
+            self.joypad.take_falling_edge();
+            self.state = CpuState::Stopped;
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.ppu.set_palette(palette);
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: bool) {
+        self.ppu.set_color_correction(color_correction);
+    }
+
+    /// Only has an effect if a DMG game runs on the CGB without a boot ROM. `None` restores the
+    /// colours chosen for the cartridge.
+    pub fn set_compatibility_palette(&mut self, palette: Option<CompatibilityPalette>) {
+        if self.ppu.has_compatibility_palettes() {
+            let colors = match palette {
+                Some(palette) => palette.colors(),
+                None => CompatibilityColors::for_cartridge(&self.cartridge),
+            };
+            self.ppu.set_compatibility_palettes(colors);
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.joypad.set_button(button, pressed);
+    }
+
+    fn next_interrupt(&self) -> Option<Interrupt> {
+        if self.interrupt.master_enable {
+            self.interrupt.pending()
+        } else {
+            None
+        }
+    }
+
+    fn dispatch_interrupt(&mut self, interrupt: Interrupt) -> u8 {
+        let pc = self.registers.pc();
+        let sp = self.registers.sp();
+        debug!("{:#06X}: interrupt {:?}", pc, interrupt);
+        self.interrupt.disable();
+        self.interrupt.acknowledge(interrupt);
+        memory_op::push_u16_stack(self, pc, sp);
+        self.registers.set_sp(sp.wrapping_sub(2));
+        self.registers.set_pc(interrupt.vector());
+        INTERRUPT_DISPATCH_TICKS
     }
 
     pub fn init_memory() -> Vec<Memory> {
-        vec![
-            Memory::new_read_write(&[0u8; 0], 0xC000, 0xDFFF),
-            Memory::new_read_write(&[0u8; 0], 0xE000, 0xFDFF),
-            Memory::new_read_write(&[0u8; 0], 0xFF80, 0xFFFE),
-            Memory::new_read_write(&[0u8; 0], 0xFFFF, 0xFFFF),
-        ]
+        vec![Memory::new_read_write(&[0u8; 0], 0xFF80, 0xFFFE)]
     }
 
     fn init_boot_state(&mut self, boot_sequence: bool) {
         use crate::util::memory_op::write_memory;
         if !boot_sequence {
+            write_memory(self, 0xFF26, 0xF1);
             write_memory(self, 0xFF05, 0x00);
             write_memory(self, 0xFF06, 0x00);
             write_memory(self, 0xFF07, 0x00);
@@ -96,7 +330,6 @@ impl Cpu {
             write_memory(self, 0xFF23, 0xBF);
             write_memory(self, 0xFF24, 0x77);
             write_memory(self, 0xFF25, 0xF3);
-            write_memory(self, 0xFF26, 0xF1);
             write_memory(self, 0xFF40, 0x91);
             write_memory(self, 0xFF42, 0x00);
             write_memory(self, 0xFF43, 0x00);
@@ -110,14 +343,58 @@ impl Cpu {
         }
     }
 
+    /// The CGB boot ROM leaves A at 0x11, which games use to detect the CGB.
+    fn init_cgb_registers(&mut self, cgb_mode: bool) {
+        self.registers.set_af(0x1180);
+        self.registers.set_bc(0x0000);
+        if cgb_mode {
+            self.registers.set_de(0xFF56);
+            self.registers.set_hl(0x000D);
+        } else {
+            self.registers.set_de(0x0008);
+            self.registers.set_hl(0x007C);
+        }
+    }
+
     pub fn game_title(&self) -> &str {
         self.cartridge.title()
     }
-}
 
-impl MapsMemory for Cpu {
-    fn read(&self, address: u16) -> Result<u8, ()> {
-        if let Some(boot) = &self.boot_rom {
+    pub fn rumble(&self) -> bool {
+        self.cartridge.rumble()
+    }
+
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.cartridge.save_ram()
+    }
+
+    pub fn load_ram(&mut self, data: &[u8]) {
+        self.cartridge.load_ram(data);
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        state::write_state(self, self.cartridge.rom_checksum())
+    }
+
+    /// Leaves the CPU untouched if the state can't be restored.
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let checksum = self.cartridge.rom_checksum();
+        let backup = self.snapshot();
+        let result = state::read_state(self, data, checksum);
+        if result.is_err() {
+            state::read_state(self, &backup, checksum).unwrap();
+        }
+        result
+    }
+
+    /// Reads from the memory map without the restrictions of a running OAM DMA.
+    fn read_bus(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            INTERRUPT_ENABLE_REGISTER => return Ok(self.interrupt.interrupt_enable_flags),
+            INTERRUPT_FLAG_REGISTER => return Ok(self.interrupt.read_request_flags()),
+            _ => {}
+        }
+        if let Some(boot) = self.boot_rom.as_ref().filter(|_| self.boot_rom_mapped) {
             if boot.is_in_range(address) {
                 return boot.read(address);
             }
@@ -129,10 +406,26 @@ impl MapsMemory for Cpu {
             .map(|mem| mem.read(address))
             .unwrap_or_else(|| Err(()));
         if read.is_err() {
-            if self.ppu.is_in_range(address) {
+            if self.wram.is_in_range(address) {
+                self.wram.read(address)
+            } else if self.ppu.is_in_range(address) {
                 self.ppu.read(address)
             } else if self.cartridge.is_in_range(address) {
                 self.cartridge.read(address)
+            } else if self.apu.is_in_range(address) {
+                self.apu.read(address)
+            } else if self.timer.is_in_range(address) {
+                self.timer.read(address)
+            } else if self.serial.is_in_range(address) {
+                self.serial.read(address)
+            } else if self.speed.is_in_range(address) {
+                self.speed.read(address)
+            } else if self.joypad.is_in_range(address) {
+                self.joypad.read(address)
+            } else if self.dma.is_in_range(address) {
+                self.dma.read(address)
+            } else if self.hdma.is_in_range(address) {
+                self.hdma.read(address)
             } else if self.io_registers.is_in_range(address) {
                 self.io_registers.read(address)
             } else if (0xFEA0..=0xFEFF).contains(&address) {
@@ -144,8 +437,31 @@ impl MapsMemory for Cpu {
             read
         }
     }
+}
+
+impl MapsMemory for Cpu {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match self.dma.conflicting_read(address) {
+            Some(value) => Ok(value),
+            None => self.read_bus(address),
+        }
+    }
 
     fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        if self.dma.blocks_write(address) {
+            return Ok(());
+        }
+        match address {
+            INTERRUPT_ENABLE_REGISTER => {
+                self.interrupt.interrupt_enable_flags = value;
+                return Ok(());
+            }
+            INTERRUPT_FLAG_REGISTER => {
+                self.interrupt.write_request_flags(value);
+                return Ok(());
+            }
+            _ => {}
+        }
         let write = self
             .memory
             .iter_mut()
@@ -153,17 +469,32 @@ impl MapsMemory for Cpu {
             .map(|mem| mem.write(address, value))
             .unwrap_or_else(|| Err(()));
         if write.is_ok() {
-            if (0xE000..=0xFDFF).contains(&address) {
-                self.write(address - 0x2000, value).unwrap();
-            }
             write
+        } else if self.wram.is_in_range(address) {
+            self.wram.write(address, value)
         } else if self.ppu.is_in_range(address) {
             self.ppu.write(address, value)
         } else if self.cartridge.is_in_range(address) {
             self.cartridge.write(address, value)
+        } else if self.apu.is_in_range(address) {
+            self.apu.write(address, value)
+        } else if self.timer.is_in_range(address) {
+            self.timer.write(address, value)
+        } else if self.serial.is_in_range(address) {
+            self.serial.write(address, value)
+        } else if self.speed.is_in_range(address) {
+            self.speed.write(address, value)
+        } else if self.joypad.is_in_range(address) {
+            self.joypad.write(address, value)
+        } else if self.dma.is_in_range(address) {
+            self.dma.write(address, value)
+        } else if self.hdma.is_in_range(address) {
+            self.hdma.write(address, value)?;
+            self.step_hdma();
+            Ok(())
         } else if self.io_registers.is_in_range(address) {
             if address == 0xFF50 {
-                self.boot_rom = None;
+                self.boot_rom_mapped = false;
             }
             self.io_registers.write(address, value)
         } else if (0xFEA0..=0xFEFF).contains(&address) {
@@ -175,18 +506,89 @@ impl MapsMemory for Cpu {
 
     fn is_in_range(&self, address: u16) -> bool {
         let mut read = self.memory.iter().any(|mem| mem.is_in_range(address));
+        read |= self.wram.is_in_range(address);
+        read |= address == INTERRUPT_ENABLE_REGISTER;
         read |= self.cartridge.is_in_range(address);
         read |= self.ppu.is_in_range(address);
         read
     }
 }
 
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        self.interrupt.save_state(writer);
+        for memory in &self.memory {
+            memory.save_state(writer);
+        }
+        self.wram.save_state(writer);
+        self.io_registers.save_state(writer);
+        writer.write_bool(self.boot_rom_mapped);
+        self.ppu.save_state(writer);
+        self.apu.save_state(writer);
+        self.timer.save_state(writer);
+        self.serial.save_state(writer);
+        self.speed.save_state(writer);
+        self.joypad.save_state(writer);
+        self.dma.save_state(writer);
+        self.hdma.save_state(writer);
+        self.cartridge.save_state(writer);
+        writer.write_u8(self.state as u8);
+        writer.write_bool(self.halt_bug);
+        writer.write_i64(self.cpu_wait_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(reader)?;
+        self.interrupt.load_state(reader)?;
+        for memory in &mut self.memory {
+            memory.load_state(reader)?;
+        }
+        self.wram.load_state(reader)?;
+        self.io_registers.load_state(reader)?;
+        self.boot_rom_mapped = reader.read_bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::BootRomMissing);
+        }
+        self.ppu.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.speed.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.dma.load_state(reader)?;
+        self.hdma.load_state(reader)?;
+        self.cartridge.load_state(reader)?;
+        self.state = CpuState::from_bits(reader.read_u8()?).ok_or(StateError::Corrupted)?;
+        self.halt_bug = reader.read_bool()?;
+        self.cpu_wait_cycles = reader.read_i64()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        gpu::screen::ScreenFetcher,
-        mem::cartridge::Cartridge,
-        processor::{cpu::Cpu, interrupt_controller::InterruptController},
+        apu::wav::write_wav,
+        emulator::{
+            gameboy::{Emulator, Gameboy},
+            model::Model,
+        },
+        gpu::{
+            compatibility::CompatibilityPalette,
+            palette::Palette,
+            ppu::{TICKS_PER_CYCLE, TICKS_PER_LINE},
+            screen::ScreenFetcher,
+        },
+        input::joypad::Button,
+        mem::cartridge::{
+            Cartridge, CartridgeError, CgbSupport, Clock, Destination, Mapper, NINTENDO_LOGO,
+        },
+        processor::{
+            cpu::{Cpu, CpuState},
+            interrupt_controller::InterruptController,
+        },
+        state::StateError,
         util::memory_op::*,
     };
     use log::LevelFilter;
@@ -198,31 +600,156 @@ mod tests {
     };
 
     fn create_cpu(rom: Vec<u8>) -> Cpu {
+        create_cpu_with_screen(rom).0
+    }
+
+    fn create_cpu_with_screen(rom: Vec<u8>) -> (Cpu, Rc<RefCell<ScreenFetcher>>) {
+        create_cpu_with_model(rom, Model::Dmg)
+    }
+
+    /// On the CGB the cartridge is marked as a CGB game, so it runs in CGB mode.
+    fn create_cpu_with_model(rom: Vec<u8>, model: Model) -> (Cpu, Rc<RefCell<ScreenFetcher>>) {
         let logger = TestLogger::init(LevelFilter::Debug, Config::default());
         if logger.is_ok() {
             logger.unwrap();
         }
         let interrupt = InterruptController::new();
-        let rom = add_header(rom);
-        let cartridge = Cartridge::new(rom);
+        let mut rom = add_header(rom);
+        if model == Model::Cgb {
+            rom[0x143] = 0x80;
+            set_checksums(&mut rom, 0);
+        }
+        let cartridge = Cartridge::new(rom).unwrap();
         let lcd_fetcher = Rc::new(RefCell::new(ScreenFetcher::new()));
-        let mut cpu = Cpu::new(interrupt, cartridge, lcd_fetcher, None);
+        let mut cpu = Cpu::new(interrupt, cartridge, lcd_fetcher.clone(), None, model);
         cpu.registers.set_pc(0);
         cpu.registers.set_f(0x0);
-        cpu
+        (cpu, lcd_fetcher)
+    }
+
+    /// A DMG game showing one tile of shade 1 at the top left.
+    fn create_compatibility_cpu(
+        title: &[u8],
+        old_licensee: u8,
+        new_licensee: &[u8; 2],
+        model: Model,
+    ) -> (Cpu, Rc<RefCell<ScreenFetcher>>) {
+        let mut rom = add_header(vec![0x18, 0xFE]); // JR -2
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x144..=0x145].copy_from_slice(new_licensee);
+        rom[0x14B] = old_licensee;
+        set_checksums(&mut rom, 0);
+        let cartridge = Cartridge::new(rom).unwrap();
+        let lcd_fetcher = Rc::new(RefCell::new(ScreenFetcher::new()));
+        let interrupt = InterruptController::new();
+        let mut cpu = Cpu::new(interrupt, cartridge, lcd_fetcher.clone(), None, model);
+        cpu.registers.set_pc(0);
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        write_memory(&mut cpu, 0xFF47, 0b1110_0100);
+        write_tile_row(&mut cpu, 1, 0, 0xFF, 0x00);
+        write_memory(&mut cpu, 0x9800, 1);
+        (cpu, lcd_fetcher)
+    }
+
+    fn run_frames(frames: usize, cpu: &mut Cpu) {
+        for _ in 0..frames * TICKS_PER_CYCLE {
+            cpu.step();
+        }
+    }
+
+    fn run_lines(lines: usize, cpu: &mut Cpu) {
+        for _ in 0..lines * TICKS_PER_LINE {
+            cpu.step();
+        }
+    }
+
+    fn screen_pixel(lcd_fetcher: &Rc<RefCell<ScreenFetcher>>, x: u32, y: u32) -> u8 {
+        lcd_fetcher.borrow().image().get_pixel(x, y).data[0]
+    }
+
+    fn write_tile_row(cpu: &mut Cpu, tile: u16, row: u16, data0: u8, data1: u8) {
+        write_memory(cpu, 0x8000 + tile * 0x10 + row * 2, data0);
+        write_memory(cpu, 0x8000 + tile * 0x10 + row * 2 + 1, data1);
+    }
+
+    fn write_color(cpu: &mut Cpu, specification: u16, palette: u8, color: u8, value: u16) {
+        write_memory(cpu, specification, 0x80 | (palette * 8 + color * 2));
+        write_memory(cpu, specification + 1, value as u8);
+        write_memory(cpu, specification + 1, (value >> 8) as u8);
+    }
+
+    fn write_sprite(cpu: &mut Cpu, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        write_memory(cpu, 0xFE00 + index * 4, y);
+        write_memory(cpu, 0xFE00 + index * 4 + 1, x);
+        write_memory(cpu, 0xFE00 + index * 4 + 2, tile);
+        write_memory(cpu, 0xFE00 + index * 4 + 3, flags);
     }
 
     fn add_header(rom: Vec<u8>) -> Vec<u8> {
-        let mut with_header = vec![0u8; 0x150];
+        let mut with_header = vec![0u8; 0x8000];
         with_header[0x147] = 0x01;
         with_header[0x148] = 0x00;
         with_header[0x149] = 0x00;
         for (i, byte) in rom.iter().enumerate() {
             with_header[i] = *byte;
         }
+        set_checksums(&mut with_header, 0);
         with_header
     }
 
+    /// Fills in the header and global checksum of the header starting at `offset`.
+    fn set_checksums(rom: &mut [u8], offset: usize) {
+        let header = &mut rom[offset..];
+        header[0x14D] = header[0x134..0x14D]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+        header[0x14E] = 0;
+        header[0x14F] = 0;
+        let global = rom
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(u16::from(byte)));
+        rom[offset + 0x14E] = (global >> 8) as u8;
+        rom[offset + 0x14F] = global as u8;
+    }
+
+    /// Creates a ROM in which every bank stores its number in its last two bytes.
+    fn create_banked_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let banks = 2usize << rom_size;
+        let mut rom = vec![0u8; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000 + 0x3FFE] = (bank >> 8) as u8;
+            rom[bank * 0x4000 + 0x3FFF] = bank as u8;
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = ram_size;
+        set_checksums(&mut rom, 0);
+        rom
+    }
+
+    fn create_banked_cpu(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Cpu {
+        let rom = create_banked_rom(cartridge_type, rom_size, ram_size);
+        create_cartridge_cpu(Cartridge::new(rom).unwrap())
+    }
+
+    fn create_cartridge_cpu(cartridge: Cartridge) -> Cpu {
+        create_cartridge_cpu_with_model(cartridge, Model::Dmg)
+    }
+
+    fn create_cartridge_cpu_with_model(cartridge: Cartridge, model: Model) -> Cpu {
+        let interrupt = InterruptController::new();
+        let lcd_fetcher = Rc::new(RefCell::new(ScreenFetcher::new()));
+        Cpu::new(interrupt, cartridge, lcd_fetcher, None, model)
+    }
+
+    struct TestClock(Arc<Mutex<u64>>);
+
+    impl Clock for TestClock {
+        fn now(&self) -> u64 {
+            *self.0.lock().unwrap()
+        }
+    }
+
     fn run_steps_without_wait_cycles(steps: usize, cpu: &mut Cpu) {
         for _ in 0..steps {
             cpu.step();
@@ -440,12 +967,12 @@ mod tests {
     fn ld_a_mem_nn() {
         let rom = vec![
             0b00_100_001,
-            0x44,
-            0xFF, // LD HL, 0xFF44
+            0x80,
+            0xFF, // LD HL, 0xFF80
             0b_00_110_110,
             0x2F, // LD (HL), 0x2F
             0b_11_111_010,
-            0x44,
+            0x80,
             0xFF, // LD A, (nn)
         ];
         let mut cpu = create_cpu(rom);
@@ -461,13 +988,13 @@ mod tests {
             0b00_111_110,
             0x3A, // LD A, 0x3A
             0b11_101_010,
-            0x44,
+            0x80,
             0xFF, // LD (nn), A
         ];
         let mut cpu = create_cpu(rom);
         run_steps_without_wait_cycles(2, &mut cpu);
         let registers = &cpu.registers;
-        assert_eq!(read_memory(&cpu, 0xFF44), 0x3A);
+        assert_eq!(read_memory(&cpu, 0xFF80), 0x3A);
         assert_eq!(registers.pc(), 5);
     }
 
@@ -2489,11 +3016,1458 @@ mod tests {
         let rom = vec![
             0b11_110_011, // DI
             0b11_111_011, // EI
+            0b00_000_000, // NOP
         ];
         let mut cpu = create_cpu(rom);
         run_steps_without_wait_cycles(2, &mut cpu);
+        assert!(!cpu.interrupt.master_enable);
+        run_steps_without_wait_cycles(1, &mut cpu);
         let registers = &cpu.registers;
         assert_eq!(cpu.interrupt.master_enable, true);
+        assert_eq!(registers.pc(), 3);
+    }
+
+    #[test]
+    fn halt_until_interrupt() {
+        let rom = vec![
+            0b11_111_011, // EI
+            0b01_110_110, // HALT
+            0b00_000_000, // NOP
+        ];
+        let mut cpu = create_cpu(rom);
+        cpu.registers.set_sp(0xFFFE);
+        write_memory(&mut cpu, 0xFFFF, 0b0000_0001);
+        run_steps_without_wait_cycles(5, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Halted);
+        assert_eq!(cpu.registers.pc(), 2);
+        write_memory(&mut cpu, 0xFF0F, 0b0000_0001);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Running);
+        assert_eq!(cpu.registers.pc(), 0x0040);
+        assert_eq!(read_memory(&cpu, 0xFFFC), 0x02);
+    }
+
+    #[test]
+    fn halt_without_ime_resumes() {
+        let rom = vec![
+            0b01_110_110, // HALT
+            0b00_000_000, // NOP
+        ];
+        let mut cpu = create_cpu(rom);
+        write_memory(&mut cpu, 0xFFFF, 0b0000_0100);
+        run_steps_without_wait_cycles(3, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Halted);
+        write_memory(&mut cpu, 0xFF0F, 0b0000_0100);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Running);
+        assert_eq!(cpu.registers.pc(), 2);
+    }
+
+    #[test]
+    fn halt_bug() {
+        let rom = vec![
+            0b01_110_110, // HALT
+            0b00_111_100, // INC A
+        ];
+        let mut cpu = create_cpu(rom);
+        cpu.registers.set_a(0);
+        write_memory(&mut cpu, 0xFFFF, 0b0000_0001);
+        write_memory(&mut cpu, 0xFF0F, 0b0000_0001);
+        run_steps_without_wait_cycles(3, &mut cpu);
+        let registers = &cpu.registers;
+        assert_eq!(cpu.state, CpuState::Running);
+        assert_eq!(registers.a(), 2);
         assert_eq!(registers.pc(), 2);
     }
+
+    #[test]
+    fn stop_until_joypad() {
+        let rom = vec![
+            0b00_010_000,
+            0b00_000_000, // STOP
+            0b00_000_000, // NOP
+        ];
+        let mut cpu = create_cpu(rom);
+        write_memory(&mut cpu, 0xFF00, 0x20);
+        run_steps_without_wait_cycles(3, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Stopped);
+        assert_eq!(cpu.registers.pc(), 2);
+        cpu.set_button(Button::Down, true);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Running);
+        assert_eq!(cpu.registers.pc(), 3);
+
+        // A button held before STOP doesn't end it, selecting its group does
+        cpu.registers.set_pc(0);
+        cpu.set_button(Button::A, true);
+        run_steps_without_wait_cycles(3, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Stopped);
+        write_memory(&mut cpu, 0xFF00, 0x30);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Stopped);
+        write_memory(&mut cpu, 0xFF00, 0x10);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        assert_eq!(cpu.state, CpuState::Running);
+    }
+
+    #[test]
+    fn double_speed_switch() {
+        let rom = vec![
+            0x3E, 0x01, // LD A, 1
+            0xE0, 0x4D, // LDH (KEY1), A
+            0x10, 0x00, // STOP
+            0x18, 0xFE, // JR -2
+        ];
+        let (mut cpu, _) = create_cpu_with_model(rom, Model::Cgb);
+        assert_eq!(read_memory(&cpu, 0xFF4D), 0x7E);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF4D), 0x7F);
+        cpu.step();
+        assert_eq!(cpu.state, CpuState::Running);
+        assert_eq!(read_memory(&cpu, 0xFF4D), 0xFE);
+        run_lines(20, &mut cpu);
+        assert_eq!(cpu.registers.pc(), 6);
+
+        // The timer runs at twice the rate, the PPU keeps its own
+        write_memory(&mut cpu, 0xFF04, 0x00);
+        let line = read_memory(&cpu, 0xFF44);
+        run_lines(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF44), line + 1);
+        assert_eq!(
+            usize::from(read_memory(&cpu, 0xFF04)),
+            2 * TICKS_PER_LINE / 0x100
+        );
+
+        write_memory(&mut cpu, 0xFF01, 0x00);
+        write_memory(&mut cpu, 0xFF02, 0x81);
+        for _ in 0..4 * 512 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFF01), 0xFF);
+    }
+
+    #[test]
+    fn double_speed_needs_cgb_mode() {
+        let rom = vec![
+            0x3E, 0x01, // LD A, 1
+            0xE0, 0x4D, // LDH (KEY1), A
+            0x10, 0x00, // STOP
+        ];
+        let (mut cpu, _) = create_cpu_with_model(rom.clone(), Model::Dmg);
+        run_steps_without_wait_cycles(4, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF4D), 0xFF);
+        assert_eq!(cpu.state, CpuState::Stopped);
+
+        // A DMG game on the CGB runs in compatibility mode
+        let mut cpu =
+            create_cartridge_cpu_with_model(Cartridge::new(add_header(rom)).unwrap(), Model::Cgb);
+        assert_eq!(cpu.registers.a(), 0x11);
+        cpu.registers.set_pc(0);
+        run_steps_without_wait_cycles(4, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF4D), 0xFF);
+        assert_eq!(cpu.state, CpuState::Stopped);
+    }
+
+    #[test]
+    fn serial_transfer() {
+        let mut cpu = create_cpu(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF01, 0x42);
+        write_memory(&mut cpu, 0xFF02, 0x81);
+        assert_eq!(read_memory(&cpu, 0xFF02), 0xFF);
+        for _ in 0..4 * 512 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFF01), 0x2F);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b1000, 0);
+        for _ in 0..4 * 512 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFF01), 0xFF);
+        assert_eq!(read_memory(&cpu, 0xFF02), 0x7F);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b1000, 0b1000);
+
+        // Without a link partner an external clock never finishes the transfer
+        write_memory(&mut cpu, 0xFF02, 0x80);
+        run_frames(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF02), 0xFE);
+    }
+
+    #[test]
+    fn vram_banking() {
+        let (mut cpu, _) = create_cpu_with_model(vec![], Model::Cgb);
+        write_memory(&mut cpu, 0x8000, 0x11);
+        assert_eq!(read_memory(&cpu, 0xFF4F), 0xFE);
+        write_memory(&mut cpu, 0xFF4F, 0x01);
+        assert_eq!(read_memory(&cpu, 0xFF4F), 0xFF);
+        assert_eq!(read_memory(&cpu, 0x8000), 0x00);
+        write_memory(&mut cpu, 0x9FFF, 0x22);
+        write_memory(&mut cpu, 0xFF4F, 0x00);
+        assert_eq!(read_memory(&cpu, 0x8000), 0x11);
+        assert_eq!(read_memory(&cpu, 0x9FFF), 0x00);
+
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0x8000, 0x11);
+        write_memory(&mut cpu, 0xFF4F, 0x01);
+        assert_eq!(read_memory(&cpu, 0xFF4F), 0xFF);
+        assert_eq!(read_memory(&cpu, 0x8000), 0x11);
+    }
+
+    #[test]
+    fn wram_banking() {
+        let (mut cpu, _) = create_cpu_with_model(vec![], Model::Cgb);
+        assert_eq!(read_memory(&cpu, 0xFF70), 0xF8);
+        for bank in 0..8 {
+            write_memory(&mut cpu, 0xFF70, bank);
+            write_memory(&mut cpu, 0xD000, 0x10 + bank);
+        }
+        write_memory(&mut cpu, 0xC000, 0x42);
+        for bank in 1..8 {
+            write_memory(&mut cpu, 0xFF70, bank);
+            assert_eq!(read_memory(&cpu, 0xFF70), 0xF8 | bank);
+            assert_eq!(read_memory(&cpu, 0xD000), 0x10 + bank);
+            assert_eq!(read_memory(&cpu, 0xF000), 0x10 + bank);
+            assert_eq!(read_memory(&cpu, 0xC000), 0x42);
+        }
+        // Selecting bank 0 maps bank 1
+        write_memory(&mut cpu, 0xFF70, 0);
+        assert_eq!(read_memory(&cpu, 0xD000), 0x11);
+        write_memory(&mut cpu, 0xFF70, 3);
+        write_memory(&mut cpu, 0xF001, 0x33);
+        assert_eq!(read_memory(&cpu, 0xD001), 0x33);
+
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xD000, 0x11);
+        write_memory(&mut cpu, 0xFF70, 0x02);
+        assert_eq!(read_memory(&cpu, 0xFF70), 0xFF);
+        assert_eq!(read_memory(&cpu, 0xD000), 0x11);
+        write_memory(&mut cpu, 0xC123, 0x22);
+        assert_eq!(read_memory(&cpu, 0xE123), 0x22);
+    }
+
+    #[test]
+    fn cgb_palette_ram() {
+        let (mut cpu, _) = create_cpu_with_model(vec![], Model::Cgb);
+        write_memory(&mut cpu, 0xFF68, 0x80);
+        for value in 0..8 {
+            write_memory(&mut cpu, 0xFF69, value);
+        }
+        assert_eq!(read_memory(&cpu, 0xFF68), 0xC8);
+        write_memory(&mut cpu, 0xFF68, 0x02);
+        assert_eq!(read_memory(&cpu, 0xFF69), 0x02);
+        assert_eq!(read_memory(&cpu, 0xFF69), 0x02);
+        write_memory(&mut cpu, 0xFF69, 0x55);
+        assert_eq!(read_memory(&cpu, 0xFF68), 0x42);
+        assert_eq!(read_memory(&cpu, 0xFF69), 0x55);
+        // Index wraps around after the last byte
+        write_memory(&mut cpu, 0xFF6A, 0xBF);
+        write_memory(&mut cpu, 0xFF6B, 0x12);
+        assert_eq!(read_memory(&cpu, 0xFF6A), 0xC0);
+        write_memory(&mut cpu, 0xFF6A, 0x3F);
+        assert_eq!(read_memory(&cpu, 0xFF6B), 0x12);
+
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF68, 0x80);
+        write_memory(&mut cpu, 0xFF69, 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF68), 0xFF);
+        assert_eq!(read_memory(&cpu, 0xFF69), 0xFF);
+        assert_eq!(read_memory(&cpu, 0xFF6A), 0xFF);
+        assert_eq!(read_memory(&cpu, 0xFF6B), 0xFF);
+    }
+
+    #[test]
+    fn cgb_background_attributes() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_model(vec![0x18, 0xFE], Model::Cgb); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        write_color(&mut cpu, 0xFF68, 1, 3, 0x001F);
+        write_color(&mut cpu, 0xFF68, 2, 3, 0x03E0);
+        write_tile_row(&mut cpu, 1, 0, 0x80, 0x80);
+        write_tile_row(&mut cpu, 1, 7, 0x40, 0x40);
+        write_memory(&mut cpu, 0xFF4F, 1);
+        write_tile_row(&mut cpu, 1, 0, 0x01, 0x01);
+        write_memory(&mut cpu, 0x9800, 0x01);
+        write_memory(&mut cpu, 0x9801, 0x0A);
+        write_memory(&mut cpu, 0x9802, 0x21);
+        write_memory(&mut cpu, 0x9803, 0x41);
+        write_memory(&mut cpu, 0xFF4F, 0);
+        for tile in 0..4 {
+            write_memory(&mut cpu, 0x9800 + tile, 1);
+        }
+        run_frames(2, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        let red = [255, 0, 0, 255];
+        let green = [0, 255, 0, 255];
+        let white = [255, 255, 255, 255];
+        assert_eq!(image.get_pixel(0, 0).data, red);
+        assert_eq!(image.get_pixel(1, 0).data, white);
+        // Tile data from bank 1 with palette 2
+        assert_eq!(image.get_pixel(8, 0).data, white);
+        assert_eq!(image.get_pixel(15, 0).data, green);
+        // Horizontal flip
+        assert_eq!(image.get_pixel(16, 0).data, white);
+        assert_eq!(image.get_pixel(23, 0).data, red);
+        // Vertical flip
+        assert_eq!(image.get_pixel(24, 0).data, white);
+        assert_eq!(image.get_pixel(25, 0).data, red);
+    }
+
+    #[test]
+    fn cgb_object_priority() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_model(vec![0x18, 0xFE], Model::Cgb); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0011);
+        write_color(&mut cpu, 0xFF68, 0, 1, 0x001F);
+        write_color(&mut cpu, 0xFF6A, 0, 1, 0x7C00);
+        write_color(&mut cpu, 0xFF6A, 1, 1, 0x03E0);
+        write_tile_row(&mut cpu, 1, 0, 0x0F, 0x00);
+        write_tile_row(&mut cpu, 2, 0, 0xFF, 0x00);
+        write_memory(&mut cpu, 0x9800, 1);
+        write_memory(&mut cpu, 0x9801, 1);
+        write_memory(&mut cpu, 0xFF4F, 1);
+        write_memory(&mut cpu, 0x9801, 0x80);
+        write_memory(&mut cpu, 0xFF4F, 0);
+        // Behind the background through the OAM flag
+        write_sprite(&mut cpu, 0, 16, 8, 2, 0x80);
+        // Behind the background through the BG attribute
+        write_sprite(&mut cpu, 1, 16, 16, 2, 0x00);
+        // The lower OAM index wins regardless of X
+        write_sprite(&mut cpu, 2, 16, 44, 2, 0x01);
+        write_sprite(&mut cpu, 3, 16, 40, 2, 0x00);
+        run_frames(2, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        let red = [255, 0, 0, 255];
+        let green = [0, 255, 0, 255];
+        let blue = [0, 0, 255, 255];
+        assert_eq!(image.get_pixel(0, 0).data, blue);
+        assert_eq!(image.get_pixel(4, 0).data, red);
+        assert_eq!(image.get_pixel(8, 0).data, blue);
+        assert_eq!(image.get_pixel(12, 0).data, red);
+        assert_eq!(image.get_pixel(35, 0).data, blue);
+        assert_eq!(image.get_pixel(37, 0).data, green);
+        // LCDC bit 0 cleared puts every sprite on top
+        write_memory(&mut cpu, 0xFF40, 0b1001_0010);
+        run_frames(1, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        assert_eq!(image.get_pixel(4, 0).data, blue);
+        assert_eq!(image.get_pixel(12, 0).data, blue);
+    }
+
+    #[test]
+    fn color_correction() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_model(vec![0x18, 0xFE], Model::Cgb); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        run_frames(2, &mut cpu);
+        assert_eq!(
+            lcd_fetcher.borrow().image().get_pixel(0, 0).data,
+            [255, 255, 255, 255]
+        );
+        cpu.set_color_correction(true);
+        run_frames(1, &mut cpu);
+        assert_eq!(
+            lcd_fetcher.borrow().image().get_pixel(0, 0).data,
+            [240, 240, 240, 255]
+        );
+    }
+
+    #[test]
+    fn hdma_general_purpose() {
+        let (mut cpu, _) = create_cpu_with_model(vec![], Model::Cgb);
+        for offset in 0..0x20 {
+            write_memory(&mut cpu, 0xC100 + offset, offset as u8 + 1);
+        }
+        write_memory(&mut cpu, 0xFF51, 0xC1);
+        write_memory(&mut cpu, 0xFF52, 0x0F);
+        write_memory(&mut cpu, 0xFF53, 0xE8);
+        write_memory(&mut cpu, 0xFF54, 0x1F);
+        let wait_cycles = cpu.cpu_wait_cycles;
+        write_memory(&mut cpu, 0xFF55, 0x01);
+        assert_eq!(cpu.cpu_wait_cycles - wait_cycles, 2 * 32);
+        assert_eq!(read_memory(&cpu, 0xFF55), 0xFF);
+        // The low nibbles and the upper bits of the destination are ignored
+        for offset in 0..0x20 {
+            assert_eq!(read_memory(&cpu, 0x8810 + offset), offset as u8 + 1);
+        }
+        assert_eq!(read_memory(&cpu, 0x8830), 0x00);
+        // Source and destination continue after the last block
+        write_memory(&mut cpu, 0xC120, 0x42);
+        write_memory(&mut cpu, 0xFF55, 0x00);
+        assert_eq!(read_memory(&cpu, 0x8830), 0x42);
+
+        let (mut cpu, _) = create_cpu_with_model(vec![], Model::Cgb);
+        write_memory(&mut cpu, 0xFF4D, 0x01);
+        cpu.stop();
+        let wait_cycles = cpu.cpu_wait_cycles;
+        write_memory(&mut cpu, 0xFF55, 0x00);
+        assert_eq!(cpu.cpu_wait_cycles - wait_cycles, 64);
+    }
+
+    #[test]
+    fn hdma_h_blank() {
+        let (mut cpu, _) = create_cpu_with_model(vec![0x18, 0xFE], Model::Cgb); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        for offset in 0..0x30 {
+            write_memory(&mut cpu, 0xC000 + offset, 0xA0 + offset as u8);
+        }
+        write_memory(&mut cpu, 0xFF51, 0xC0);
+        write_memory(&mut cpu, 0xFF52, 0x00);
+        write_memory(&mut cpu, 0xFF53, 0x00);
+        write_memory(&mut cpu, 0xFF54, 0x00);
+        write_memory(&mut cpu, 0xFF55, 0x82);
+        assert_eq!(read_memory(&cpu, 0xFF55), 0x02);
+        assert_eq!(read_memory(&cpu, 0x8000), 0x00);
+        run_lines(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF55), 0x01);
+        assert_eq!(read_memory(&cpu, 0x800F), 0xAF);
+        assert_eq!(read_memory(&cpu, 0x8010), 0x00);
+        // Writing bit 7 = 0 cancels the transfer and keeps the remaining length
+        write_memory(&mut cpu, 0xFF55, 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF55), 0x81);
+        run_lines(2, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0x8010), 0x00);
+        write_memory(&mut cpu, 0xFF55, 0x81);
+        run_lines(2, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF55), 0xFF);
+        assert_eq!(read_memory(&cpu, 0x8010), 0xB0);
+        assert_eq!(read_memory(&cpu, 0x802F), 0xCF);
+        run_lines(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0x8030), 0x00);
+
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xC000, 0x11);
+        write_memory(&mut cpu, 0xFF51, 0xC0);
+        write_memory(&mut cpu, 0xFF55, 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF55), 0xFF);
+        assert_eq!(read_memory(&cpu, 0x8000), 0x00);
+    }
+
+    #[test]
+    fn compatibility_palettes() {
+        let background_color = |title: &[u8], old_licensee: u8, new_licensee: &[u8; 2]| {
+            let (mut cpu, lcd_fetcher) =
+                create_compatibility_cpu(title, old_licensee, new_licensee, Model::Cgb);
+            run_frames(2, &mut cpu);
+            let color = lcd_fetcher.borrow().image().get_pixel(0, 0).data;
+            color
+        };
+        let default = [123, 255, 49, 255];
+        assert_eq!(background_color(b"TETRIS", 0x01, b"00"), [255, 255, 0, 255]);
+        assert_eq!(background_color(b"TETRIS", 0x33, b"01"), [255, 255, 0, 255]);
+        assert_eq!(background_color(b"TETRIS", 0x08, b"00"), default);
+        assert_eq!(background_color(b"TETRIS", 0x33, b"08"), default);
+        assert_eq!(background_color(b"UNKNOWN", 0x01, b"00"), default);
+        // Shared checksums are told apart by the fourth letter
+        assert_eq!(
+            background_color(b"POKEMON BLUE", 0x01, b"00"),
+            [99, 165, 255, 255]
+        );
+        assert_eq!(background_color(b"POEKMON BLUE", 0x01, b"00"), default);
+        // The raw title bytes are summed, even ones that aren't valid text
+        assert_eq!(
+            background_color(b"TETRI\x80\xD3", 0x01, b"00"),
+            [255, 255, 0, 255]
+        );
+
+        let (mut cpu, lcd_fetcher) = create_compatibility_cpu(b"TETRIS", 0x01, b"00", Model::Cgb);
+        cpu.set_compatibility_palette(Some(CompatibilityPalette::RightA));
+        run_frames(2, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        assert_eq!(image.get_pixel(0, 0).data, [82, 255, 0, 255]);
+        cpu.set_compatibility_palette(None);
+        run_frames(1, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        assert_eq!(image.get_pixel(0, 0).data, [255, 255, 0, 255]);
+
+        let (mut cpu, lcd_fetcher) = create_compatibility_cpu(b"TETRIS", 0x01, b"00", Model::Dmg);
+        cpu.set_compatibility_palette(Some(CompatibilityPalette::RightA));
+        run_frames(2, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 0, 0), 180);
+    }
+
+    #[test]
+    fn compatibility_object_palettes() {
+        let (mut cpu, lcd_fetcher) =
+            create_compatibility_cpu(b"POKEMON RED", 0x01, b"00", Model::Cgb);
+        write_memory(&mut cpu, 0xFF40, 0b1001_0011);
+        write_memory(&mut cpu, 0xFF48, 0b1110_0100);
+        write_memory(&mut cpu, 0xFF49, 0b1110_0100);
+        write_sprite(&mut cpu, 0, 16, 24, 1, 0x00);
+        write_sprite(&mut cpu, 1, 16, 32, 1, 0x10);
+        run_frames(2, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        assert_eq!(image.get_pixel(0, 0).data, [255, 132, 132, 255]);
+        assert_eq!(image.get_pixel(16, 0).data, [123, 255, 49, 255]);
+        assert_eq!(image.get_pixel(24, 0).data, [255, 132, 132, 255]);
+        // The palette RAM stays locked for DMG games
+        write_memory(&mut cpu, 0xFF68, 0x80);
+        write_memory(&mut cpu, 0xFF69, 0x00);
+        write_memory(&mut cpu, 0xFF69, 0x00);
+        run_frames(1, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 8, 0), 255);
+    }
+
+    #[test]
+    fn timer_divider() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF04, 0x12);
+        for _ in 0..0x200 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFF04), 0x02);
+        write_memory(&mut cpu, 0xFF04, 0x12);
+        assert_eq!(read_memory(&cpu, 0xFF04), 0x00);
+    }
+
+    #[test]
+    fn timer_overflow() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF04, 0x00);
+        write_memory(&mut cpu, 0xFF05, 0xFE);
+        write_memory(&mut cpu, 0xFF06, 0x42);
+        write_memory(&mut cpu, 0xFF07, 0b101);
+        for _ in 0..32 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFF05), 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b100, 0);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFF05), 0x42);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b100, 0b100);
+    }
+
+    #[test]
+    fn timer_divider_reset_glitch() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF04, 0x00);
+        write_memory(&mut cpu, 0xFF05, 0x00);
+        write_memory(&mut cpu, 0xFF07, 0b101);
+        for _ in 0..8 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFF05), 0x00);
+        write_memory(&mut cpu, 0xFF04, 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF05), 0x01);
+    }
+
+    #[test]
+    fn joypad_select_lines() {
+        let mut cpu = create_cpu(vec![]);
+        cpu.set_button(Button::Start, true);
+        cpu.set_button(Button::Left, true);
+        write_memory(&mut cpu, 0xFF00, 0x10);
+        assert_eq!(read_memory(&cpu, 0xFF00), 0b1101_0111);
+        write_memory(&mut cpu, 0xFF00, 0x20);
+        assert_eq!(read_memory(&cpu, 0xFF00), 0b1110_1101);
+        write_memory(&mut cpu, 0xFF00, 0x30);
+        assert_eq!(read_memory(&cpu, 0xFF00), 0b1111_1111);
+    }
+
+    #[test]
+    fn joypad_interrupt() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF0F, 0x00);
+        write_memory(&mut cpu, 0xFF00, 0x10);
+        cpu.set_button(Button::Up, true);
+        cpu.step();
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b1_0000, 0);
+        write_memory(&mut cpu, 0xFF00, 0x20);
+        cpu.step();
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b1_0000, 0b1_0000);
+        write_memory(&mut cpu, 0xFF0F, 0x00);
+        cpu.set_button(Button::Up, false);
+        cpu.step();
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b1_0000, 0);
+    }
+
+    #[test]
+    fn apu_square_wave_samples() {
+        let mut cpu = create_cpu(vec![]);
+        cpu.set_sample_rate(32_768);
+        write_memory(&mut cpu, 0xFF24, 0x77);
+        write_memory(&mut cpu, 0xFF25, 0x22);
+        write_memory(&mut cpu, 0xFF16, 0b1000_0000);
+        write_memory(&mut cpu, 0xFF17, 0xF0);
+        write_memory(&mut cpu, 0xFF18, 0x00);
+        write_memory(&mut cpu, 0xFF19, 0x87);
+        assert_eq!(read_memory(&cpu, 0xFF26) & 0b1000_0010, 0b1000_0010);
+        for _ in 0..(4_194_304 / 64) {
+            cpu.step();
+        }
+        let samples = cpu.take_audio_samples();
+        assert_eq!(samples.len(), 2 * 32_768 / 64);
+        assert!(samples.iter().any(|sample| *sample > 0.2));
+        assert!(samples.iter().any(|sample| *sample < -0.2));
+        let samples = samples
+            .iter()
+            .map(|sample| (sample * 32767.0) as i16)
+            .collect::<Vec<i16>>();
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 32_768, &samples).unwrap();
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(wav.len(), 44 + samples.len() * 2);
+    }
+
+    #[test]
+    fn sample_rate_is_clamped() {
+        let mut gameboy = Gameboy::new(None, Model::Dmg);
+        gameboy.set_sample_rate(0);
+        assert_eq!(gameboy.sample_rate(), 1);
+        gameboy.load_cartridge(Cartridge::new(add_header(vec![])).unwrap());
+        gameboy.set_sample_rate(u32::MAX);
+        assert_eq!(gameboy.sample_rate(), 4_194_304);
+    }
+
+    #[test]
+    fn apu_power_off_clears_registers() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF30, 0x5A);
+        write_memory(&mut cpu, 0xFF26, 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF24), 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF12), 0x00);
+        write_memory(&mut cpu, 0xFF12, 0xF3);
+        assert_eq!(read_memory(&cpu, 0xFF12), 0x00);
+        assert_eq!(read_memory(&cpu, 0xFF30), 0x5A);
+        assert_eq!(read_memory(&cpu, 0xFF26), 0b0111_0000);
+    }
+
+    #[test]
+    fn sprite_rendering() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0011);
+        write_memory(&mut cpu, 0xFF48, 0b1110_0100);
+        write_memory(&mut cpu, 0xFF49, 0b0001_1011);
+        write_tile_row(&mut cpu, 1, 0, 0b1000_0000, 0b0000_0000);
+        write_sprite(&mut cpu, 0, 16, 8, 1, 0b0000_0000);
+        write_sprite(&mut cpu, 1, 16, 20, 1, 0b0011_0000);
+        run_frames(2, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 0, 0), 180);
+        assert_eq!(screen_pixel(&lcd_fetcher, 1, 0), 255);
+        assert_eq!(screen_pixel(&lcd_fetcher, 19, 0), 90);
+        assert_eq!(screen_pixel(&lcd_fetcher, 12, 0), 255);
+    }
+
+    #[test]
+    fn sprite_priority_and_limit() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0011);
+        write_memory(&mut cpu, 0xFF48, 0b1110_0100);
+        write_memory(&mut cpu, 0xFF49, 0b1110_0100);
+        write_tile_row(&mut cpu, 1, 0, 0xFF, 0x00);
+        write_tile_row(&mut cpu, 2, 0, 0x00, 0xFF);
+        // lower X wins regardless of OAM order
+        write_sprite(&mut cpu, 0, 16, 12, 1, 0);
+        write_sprite(&mut cpu, 1, 16, 8, 2, 0);
+        // eleventh sprite on the line is dropped
+        for index in 2..10 {
+            write_sprite(&mut cpu, index, 16, 0, 1, 0);
+        }
+        write_sprite(&mut cpu, 10, 16, 100, 1, 0);
+        run_frames(2, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 4, 0), 90);
+        assert_eq!(screen_pixel(&lcd_fetcher, 9, 0), 180);
+        assert_eq!(screen_pixel(&lcd_fetcher, 92, 0), 255);
+    }
+
+    #[test]
+    fn sprites_lengthen_pixel_transfer() {
+        let transfer_ticks = |sprites: u16| {
+            let mut cpu = create_cpu(vec![0x18, 0xFE]); // JR -2
+            write_memory(&mut cpu, 0xFF40, 0b1001_0011);
+            for index in 0..sprites {
+                write_sprite(&mut cpu, index, 16, 8 + 20 * index as u8, 0, 0);
+            }
+            while read_memory(&cpu, 0xFF41) & 0b11 != 3 {
+                cpu.step();
+            }
+            let mut ticks = 0;
+            while read_memory(&cpu, 0xFF41) & 0b11 == 3 {
+                cpu.step();
+                ticks += 1;
+            }
+            ticks
+        };
+        // Every sprite stalls the transfer while its tile is fetched
+        assert_eq!(transfer_ticks(3), transfer_ticks(0) + 3 * 6);
+    }
+
+    #[test]
+    fn window_rendering() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1111_0001);
+        write_memory(&mut cpu, 0xFF4A, 8);
+        write_memory(&mut cpu, 0xFF4B, 7 + 80);
+        for row in 0..8 {
+            write_tile_row(&mut cpu, 1, row, 0xFF, 0xFF);
+        }
+        for tile in 0..0x400 {
+            write_memory(&mut cpu, 0x9C00 + tile, 1);
+        }
+        run_frames(2, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 80, 7), 255);
+        assert_eq!(screen_pixel(&lcd_fetcher, 79, 8), 255);
+        assert_eq!(screen_pixel(&lcd_fetcher, 80, 8), 0);
+        assert_eq!(screen_pixel(&lcd_fetcher, 159, 143), 0);
+    }
+
+    #[test]
+    fn window_left_of_screen() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1111_0001);
+        write_memory(&mut cpu, 0xFF4A, 0);
+        write_memory(&mut cpu, 0xFF4B, 3);
+        for row in 0..8 {
+            write_tile_row(&mut cpu, 1, row, 0x0F, 0x0F);
+        }
+        for tile in 0..0x400 {
+            write_memory(&mut cpu, 0x9C00 + tile, 1);
+        }
+        run_frames(2, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 0, 0), 0);
+        assert_eq!(screen_pixel(&lcd_fetcher, 3, 0), 0);
+        assert_eq!(screen_pixel(&lcd_fetcher, 4, 0), 255);
+        assert_eq!(screen_pixel(&lcd_fetcher, 8, 0), 0);
+    }
+
+    #[test]
+    fn background_scroll_x() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        write_memory(&mut cpu, 0xFF43, 0xFB);
+        write_memory(&mut cpu, 0xFF47, 0b1110_0100);
+        write_tile_row(&mut cpu, 1, 0, 0b1000_0000, 0b1000_0000);
+        write_tile_row(&mut cpu, 2, 0, 0b0000_0001, 0b0000_0000);
+        write_memory(&mut cpu, 0x9800, 1);
+        write_memory(&mut cpu, 0x981F, 2);
+        run_frames(2, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 3, 0), 255);
+        assert_eq!(screen_pixel(&lcd_fetcher, 4, 0), 180);
+        assert_eq!(screen_pixel(&lcd_fetcher, 5, 0), 0);
+        assert_eq!(screen_pixel(&lcd_fetcher, 6, 0), 255);
+    }
+
+    #[test]
+    fn interrupt_dispatch() {
+        let rom = vec![
+            0b11_111_011, // EI
+            0b00_000_000, // NOP
+            0b00_000_000, // NOP
+        ];
+        let mut cpu = create_cpu(rom);
+        cpu.registers.set_sp(0xFFFE);
+        write_memory(&mut cpu, 0xFFFF, 0b0000_0100);
+        write_memory(&mut cpu, 0xFF0F, 0b0000_0100);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        assert_eq!(cpu.registers.pc(), 2);
+        run_steps_without_wait_cycles(1, &mut cpu);
+        let registers = &cpu.registers;
+        assert_eq!(registers.pc(), 0x0050);
+        assert_eq!(registers.sp(), 0xFFFC);
+        assert_eq!(read_memory(&cpu, 0xFFFD), 0x00);
+        assert_eq!(read_memory(&cpu, 0xFFFC), 0x02);
+        assert_eq!(read_memory(&cpu, 0xFF0F), 0b1110_0000);
+        assert!(!cpu.interrupt.master_enable);
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        let rom = vec![
+            0b11_111_011, // EI
+            0b00_000_000, // NOP
+        ];
+        let mut cpu = create_cpu(rom);
+        cpu.registers.set_sp(0xFFFE);
+        write_memory(&mut cpu, 0xFFFF, 0b0001_1110);
+        write_memory(&mut cpu, 0xFF0F, 0b0001_1001);
+        run_steps_without_wait_cycles(3, &mut cpu);
+        assert_eq!(cpu.registers.pc(), 0x0058);
+        assert_eq!(read_memory(&cpu, 0xFF0F), 0b1111_0001);
+    }
+
+    #[test]
+    fn interrupt_dispatch_ticks() {
+        let rom = vec![
+            0b11_111_011, // EI
+            0b00_000_000, // NOP
+        ];
+        let mut cpu = create_cpu(rom);
+        cpu.registers.set_sp(0xFFFE);
+        write_memory(&mut cpu, 0xFFFF, 0b0000_0001);
+        write_memory(&mut cpu, 0xFF0F, 0b0000_0001);
+        run_steps_without_wait_cycles(2, &mut cpu);
+        cpu.step();
+        assert_eq!(cpu.registers.pc(), 0x0040);
+        assert_eq!(cpu.cpu_wait_cycles, 19);
+    }
+
+    #[test]
+    fn vblank_interrupt() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF0F, 0);
+        run_lines(143, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b0000_0001, 0);
+        run_lines(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF44), 144);
+        assert_eq!(read_memory(&cpu, 0xFF41) & 0b11, 1);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b0000_0001, 1);
+        run_lines(10, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF44), 0);
+        assert_eq!(read_memory(&cpu, 0xFF41) & 0b11, 2);
+    }
+
+    #[test]
+    fn ly_is_read_only() {
+        let mut cpu = create_cpu(vec![]);
+        run_lines(5, &mut cpu);
+        write_memory(&mut cpu, 0xFF44, 0x42);
+        assert_eq!(read_memory(&cpu, 0xFF44), 5);
+    }
+
+    #[test]
+    fn lyc_coincidence_interrupt() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF45, 10);
+        write_memory(&mut cpu, 0xFF41, 0b0100_0000);
+        write_memory(&mut cpu, 0xFF0F, 0);
+        run_lines(9, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF41) & 0b0100_0100, 0b0100_0000);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b0000_0010, 0);
+        run_lines(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF41) & 0b0100_0100, 0b0100_0100);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b0000_0010, 0b0000_0010);
+    }
+
+    #[test]
+    fn stat_interrupt_blocking() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF45, 3);
+        write_memory(&mut cpu, 0xFF41, 0b0110_0000);
+        run_lines(3, &mut cpu);
+        // The OAM search of line 3 starts while the LYC source is already high
+        write_memory(&mut cpu, 0xFF0F, 0);
+        run_lines(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b0000_0010, 0);
+        run_lines(1, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0xFF0F) & 0b0000_0010, 0b0000_0010);
+    }
+
+    #[test]
+    fn background_palette() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        write_memory(&mut cpu, 0xFF47, 0b0001_1011);
+        write_tile_row(&mut cpu, 1, 0, 0b0101_0000, 0b0011_0000);
+        write_memory(&mut cpu, 0x9800, 1);
+        run_frames(2, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 0, 0), 0);
+        assert_eq!(screen_pixel(&lcd_fetcher, 1, 0), 90);
+        assert_eq!(screen_pixel(&lcd_fetcher, 2, 0), 180);
+        assert_eq!(screen_pixel(&lcd_fetcher, 3, 0), 255);
+        write_memory(&mut cpu, 0xFF47, 0x00);
+        run_frames(1, &mut cpu);
+        assert_eq!(screen_pixel(&lcd_fetcher, 3, 0), 255);
+        assert_eq!(screen_pixel(&lcd_fetcher, 0, 0), 255);
+    }
+
+    #[test]
+    fn output_palette() {
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(vec![0x18, 0xFE]); // JR -2
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        write_memory(&mut cpu, 0xFF47, 0b1110_0100);
+        write_tile_row(&mut cpu, 1, 0, 0xFF, 0xFF);
+        write_memory(&mut cpu, 0x9800, 1);
+        cpu.set_palette(Palette::ClassicGreen);
+        run_frames(2, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        assert_eq!(image.get_pixel(0, 0).data, [0x0F, 0x38, 0x0F, 255]);
+        assert_eq!(image.get_pixel(8, 0).data, [0x9B, 0xBC, 0x0F, 255]);
+        cpu.set_palette(Palette::Custom([
+            [1, 2, 3],
+            [4, 5, 6],
+            [7, 8, 9],
+            [10, 11, 12],
+        ]));
+        run_frames(1, &mut cpu);
+        let image = lcd_fetcher.borrow().image().clone();
+        assert_eq!(image.get_pixel(0, 0).data, [10, 11, 12, 255]);
+        assert_eq!(image.get_pixel(8, 0).data, [1, 2, 3, 255]);
+    }
+
+    #[test]
+    fn oam_dma_transfer() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF80, 0x18); // JR -2
+        write_memory(&mut cpu, 0xFF81, 0xFE);
+        cpu.registers.set_pc(0xFF80);
+        for offset in 0..0xA0 {
+            write_memory(&mut cpu, 0xC100 + offset, offset as u8 ^ 0x5A);
+        }
+        write_memory(&mut cpu, 0xFF46, 0xC1);
+        for _ in 0..639 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xFE00), 0xFF);
+        cpu.step();
+        assert_eq!(read_memory(&cpu, 0xFF46), 0xC1);
+        for offset in 0..0xA0 {
+            assert_eq!(read_memory(&cpu, 0xFE00 + offset), offset as u8 ^ 0x5A);
+        }
+        assert_eq!(cpu.registers.pc(), 0xFF80);
+    }
+
+    #[test]
+    fn oam_dma_bus_conflicts() {
+        let mut cpu = create_cpu(vec![]);
+        write_memory(&mut cpu, 0xFF80, 0x18); // JR -2
+        write_memory(&mut cpu, 0xFF81, 0xFE);
+        cpu.registers.set_pc(0xFF80);
+        write_memory(&mut cpu, 0xC000, 0x12);
+        write_memory(&mut cpu, 0xC001, 0x34);
+        write_memory(&mut cpu, 0x8000, 0x56);
+        write_memory(&mut cpu, 0xFF46, 0xC0);
+        for _ in 0..8 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0x0000), 0x34);
+        assert_eq!(read_memory(&cpu, 0xD000), 0x34);
+        // VRAM and OAM read 0xFF, I/O, HRAM and IE stay accessible
+        assert_eq!(read_memory(&cpu, 0x8000), 0xFF);
+        assert_eq!(read_memory(&cpu, 0xFE00), 0xFF);
+        assert_eq!(read_memory(&cpu, 0xFF46), 0xC0);
+        assert_eq!(read_memory(&cpu, 0xFF81), 0xFE);
+        write_memory(&mut cpu, 0xFFFF, 0x01);
+        assert_eq!(read_memory(&cpu, 0xFFFF), 0x01);
+        write_memory(&mut cpu, 0xC050, 0x78);
+        write_memory(&mut cpu, 0xFE50, 0x78);
+        write_memory(&mut cpu, 0x8000, 0x78);
+        write_memory(&mut cpu, 0xFF47, 0x78);
+        write_memory(&mut cpu, 0xFF82, 0x78);
+        for _ in 0..640 {
+            cpu.step();
+        }
+        assert_eq!(read_memory(&cpu, 0xC050), 0x00);
+        assert_eq!(read_memory(&cpu, 0xFE01), 0x34);
+        assert_eq!(read_memory(&cpu, 0x8000), 0x56);
+        assert_eq!(read_memory(&cpu, 0xFF47), 0x78);
+        assert_eq!(read_memory(&cpu, 0xFF82), 0x78);
+    }
+
+    #[test]
+    fn mbc2_rom_banking() {
+        let mut cpu = create_banked_cpu(0x06, 0x03, 0x00);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 1);
+        write_memory(&mut cpu, 0x2100, 0x0B);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x0B);
+        assert_eq!(read_memory(&cpu, 0x3FFF), 0);
+        // bit 8 clear selects RAMG instead of ROMB
+        write_memory(&mut cpu, 0x2000, 0x03);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x0B);
+        write_memory(&mut cpu, 0x0100, 0x00);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 1);
+    }
+
+    #[test]
+    fn mbc2_ram() {
+        let mut cpu = create_banked_cpu(0x06, 0x03, 0x00);
+        write_memory(&mut cpu, 0xA000, 0x05);
+        assert_eq!(read_memory(&cpu, 0xA000), 0xFF);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0xA000, 0x35);
+        write_memory(&mut cpu, 0xA1FF, 0x0C);
+        assert_eq!(read_memory(&cpu, 0xA000), 0xF5);
+        assert_eq!(read_memory(&cpu, 0xA200), 0xF5);
+        assert_eq!(read_memory(&cpu, 0xBFFF), 0xFC);
+        write_memory(&mut cpu, 0x0000, 0x00);
+        assert_eq!(read_memory(&cpu, 0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_banking() {
+        let mut cpu = create_banked_cpu(0x13, 0x06, 0x03);
+        write_memory(&mut cpu, 0x2000, 0x7F);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x7F);
+        write_memory(&mut cpu, 0x2000, 0x00);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x01);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        for bank in 0..4 {
+            write_memory(&mut cpu, 0x4000, bank);
+            write_memory(&mut cpu, 0xA123, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            write_memory(&mut cpu, 0x4000, bank);
+            assert_eq!(read_memory(&cpu, 0xA123), 0x10 + bank);
+        }
+        write_memory(&mut cpu, 0x0000, 0x00);
+        assert_eq!(read_memory(&cpu, 0xA123), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_rtc_latch() {
+        let time = Arc::new(Mutex::new(1000));
+        let rom = create_banked_rom(0x10, 0x01, 0x03);
+        let cartridge = Cartridge::with_clock(rom, Box::new(TestClock(time.clone()))).unwrap();
+        let mut cpu = create_cartridge_cpu(cartridge);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        *time.lock().unwrap() += 2 * 86400 + 3 * 3600 + 4 * 60 + 5;
+        write_memory(&mut cpu, 0x4000, 0x08);
+        assert_eq!(read_memory(&cpu, 0xA000), 0);
+        write_memory(&mut cpu, 0x6000, 0x00);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 5);
+        write_memory(&mut cpu, 0x4000, 0x09);
+        assert_eq!(read_memory(&cpu, 0xA000), 4);
+        write_memory(&mut cpu, 0x4000, 0x0A);
+        assert_eq!(read_memory(&cpu, 0xA000), 3);
+        write_memory(&mut cpu, 0x4000, 0x0B);
+        assert_eq!(read_memory(&cpu, 0xA000), 2);
+        // the latched value stays until the next latch sequence
+        *time.lock().unwrap() += 60;
+        write_memory(&mut cpu, 0x4000, 0x09);
+        assert_eq!(read_memory(&cpu, 0xA000), 4);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 4);
+        write_memory(&mut cpu, 0x6000, 0x00);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 5);
+    }
+
+    #[test]
+    fn mbc3_rtc_halt_and_day_carry() {
+        let time = Arc::new(Mutex::new(0));
+        let rom = create_banked_rom(0x0F, 0x01, 0x00);
+        let cartridge = Cartridge::with_clock(rom, Box::new(TestClock(time.clone()))).unwrap();
+        let mut cpu = create_cartridge_cpu(cartridge);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x4000, 0x0C);
+        write_memory(&mut cpu, 0xA000, 0b0100_0001);
+        write_memory(&mut cpu, 0x4000, 0x0B);
+        write_memory(&mut cpu, 0xA000, 0xFF);
+        *time.lock().unwrap() += 3 * 86400;
+        write_memory(&mut cpu, 0x6000, 0x00);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 0xFF);
+        write_memory(&mut cpu, 0x4000, 0x0C);
+        assert_eq!(read_memory(&cpu, 0xA000), 0b0100_0001);
+        write_memory(&mut cpu, 0xA000, 0b0000_0001);
+        *time.lock().unwrap() += 86400;
+        write_memory(&mut cpu, 0x6000, 0x00);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 0b1000_0000);
+        write_memory(&mut cpu, 0x4000, 0x0B);
+        assert_eq!(read_memory(&cpu, 0xA000), 0x00);
+    }
+
+    #[test]
+    fn mbc5_rom_banking() {
+        let mut cpu = create_banked_cpu(0x19, 0x08, 0x00);
+        write_memory(&mut cpu, 0x2000, 0xFF);
+        write_memory(&mut cpu, 0x3000, 0x01);
+        assert_eq!(read_memory(&cpu, 0x7FFE), 0x01);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0xFF);
+        write_memory(&mut cpu, 0x2000, 0x00);
+        assert_eq!(read_memory(&cpu, 0x7FFE), 0x01);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x00);
+        write_memory(&mut cpu, 0x3000, 0x00);
+        assert_eq!(read_memory(&cpu, 0x7FFE), 0x00);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x00);
+    }
+
+    #[test]
+    fn mbc5_ram_and_rumble() {
+        let mut cpu = create_banked_cpu(0x1E, 0x01, 0x03);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x4000, 0x02);
+        write_memory(&mut cpu, 0xA000, 0x42);
+        assert!(!cpu.rumble());
+        write_memory(&mut cpu, 0x4000, 0x0A);
+        assert!(cpu.rumble());
+        assert_eq!(read_memory(&cpu, 0xA000), 0x42);
+        write_memory(&mut cpu, 0x4000, 0x00);
+        assert!(!cpu.rumble());
+        assert_eq!(read_memory(&cpu, 0xA000), 0x00);
+    }
+
+    #[test]
+    fn mbc5_sixteen_ram_banks() {
+        let mut cpu = create_banked_cpu(0x1B, 0x01, 0x04);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        for bank in 0..16 {
+            write_memory(&mut cpu, 0x4000, bank);
+            write_memory(&mut cpu, 0xBFFF, bank + 1);
+        }
+        for bank in 0..16 {
+            write_memory(&mut cpu, 0x4000, bank);
+            assert_eq!(read_memory(&cpu, 0xBFFF), bank + 1);
+        }
+    }
+
+    #[test]
+    fn cartridge_errors() {
+        let rom = create_banked_rom(0x01, 0x01, 0x00);
+        assert_eq!(
+            Cartridge::new(rom[..0x100].to_vec()).err(),
+            Some(CartridgeError::Truncated {
+                expected: 0x150,
+                actual: 0x100
+            })
+        );
+        assert_eq!(
+            Cartridge::new(rom[..0x8000].to_vec()).err(),
+            Some(CartridgeError::Truncated {
+                expected: 0x10000,
+                actual: 0x8000
+            })
+        );
+        let mut corrupted = rom.clone();
+        corrupted[0x134] = b'A';
+        assert!(matches!(
+            Cartridge::new(corrupted).err(),
+            Some(CartridgeError::HeaderChecksum { .. })
+        ));
+        let mut corrupted = rom.clone();
+        corrupted[0x4000] = 0xFF;
+        assert!(matches!(
+            Cartridge::new_strict(corrupted.clone()).err(),
+            Some(CartridgeError::GlobalChecksum { .. })
+        ));
+        let cartridge = Cartridge::new(corrupted).unwrap();
+        assert!(!cartridge.info().global_checksum_valid);
+        let invalid_header = |address: usize, value: u8| {
+            let mut rom = rom.clone();
+            rom[address] = value;
+            set_checksums(&mut rom, 0);
+            Cartridge::new(rom).err()
+        };
+        assert_eq!(
+            invalid_header(0x147, 0xFC),
+            Some(CartridgeError::UnknownMapper(0xFC))
+        );
+        assert_eq!(
+            invalid_header(0x148, 0x09),
+            Some(CartridgeError::InvalidRomSize(0x09))
+        );
+        assert_eq!(
+            invalid_header(0x149, 0x06),
+            Some(CartridgeError::InvalidRamSize(0x06))
+        );
+        assert!(Cartridge::new_strict(rom.clone()).is_ok());
+        assert!(Cartridge::new(rom).unwrap().info().global_checksum_valid);
+    }
+
+    #[test]
+    fn cartridge_info() {
+        let mut rom = create_banked_rom(0x10, 0x01, 0x03);
+        rom[0x134..0x143].copy_from_slice(b"PM_CRYSTAL\0BYTE");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x146] = 0x03;
+        rom[0x14A] = 0x01;
+        rom[0x14B] = 0x33;
+        rom[0x14C] = 0x01;
+        set_checksums(&mut rom, 0);
+        let info = Cartridge::new(rom).unwrap().info();
+        assert_eq!(info.title, "PM_CRYSTAL");
+        assert_eq!(info.manufacturer.as_deref(), Some("BYTE"));
+        assert_eq!(info.mapper, Mapper::Mbc3);
+        assert!(info.battery && info.rtc && !info.rumble);
+        assert_eq!(info.rom_size, 0x10000);
+        assert_eq!(info.ram_size, 0x8000);
+        assert_eq!(info.cgb, CgbSupport::Enhanced);
+        assert!(info.sgb);
+        assert_eq!(info.destination, Destination::Overseas);
+        assert_eq!(info.new_licensee_code.as_deref(), Some("01"));
+        assert_eq!(info.old_licensee, None);
+        assert_eq!(info.licensee(), Some("Nintendo Research & Development 1"));
+        assert_eq!(info.version, 1);
+
+        let mut rom = create_banked_rom(0x06, 0x01, 0x00);
+        rom[0x134..0x144].copy_from_slice(b"SIXTEEN CHAR NAM");
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x14B] = 0x01;
+        set_checksums(&mut rom, 0);
+        let info = Cartridge::new(rom).unwrap().info();
+        assert_eq!(info.title, "SIXTEEN CHAR NAM");
+        assert_eq!(info.manufacturer, None);
+        assert_eq!(info.mapper, Mapper::Mbc2);
+        assert_eq!(info.ram_size, 0x200);
+        assert_eq!(info.cgb, CgbSupport::Unsupported);
+        assert!(!info.sgb);
+        assert_eq!(info.destination, Destination::Japanese);
+        assert_eq!(info.new_licensee_code, None);
+        assert_eq!(info.licensee(), Some("Nintendo"));
+    }
+
+    #[test]
+    fn mmm01_mapping() {
+        let mut rom = create_banked_rom(0x00, 0x02, 0x00);
+        rom[0x18147] = 0x0D;
+        rom[0x18148] = 0x02;
+        rom[0x18149] = 0x02;
+        set_checksums(&mut rom, 0x18000);
+        let mut cpu = create_cartridge_cpu(Cartridge::new(rom).unwrap());
+        assert_eq!(read_memory(&cpu, 0x3FFF), 6);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 7);
+        write_memory(&mut cpu, 0x2000, 0x04);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 7);
+        // lock bank bit 2 and map the game in banks 4 to 7
+        write_memory(&mut cpu, 0x6000, 0b0000_1000);
+        write_memory(&mut cpu, 0x0000, 0b0100_0000);
+        assert_eq!(read_memory(&cpu, 0x3FFF), 4);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 4);
+        write_memory(&mut cpu, 0x2000, 0x01);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 5);
+        write_memory(&mut cpu, 0x2000, 0x03);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 7);
+        // the mapping can't be changed anymore
+        write_memory(&mut cpu, 0x6000, 0x00);
+        write_memory(&mut cpu, 0x0000, 0x00);
+        write_memory(&mut cpu, 0x2000, 0x00);
+        assert_eq!(read_memory(&cpu, 0x3FFF), 4);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 4);
+    }
+
+    #[test]
+    fn mmm01_ram() {
+        let mut rom = create_banked_rom(0x00, 0x02, 0x00);
+        rom[0x18147] = 0x0D;
+        rom[0x18148] = 0x02;
+        rom[0x18149] = 0x03;
+        set_checksums(&mut rom, 0x18000);
+        let mut cpu = create_cartridge_cpu(Cartridge::new(rom).unwrap());
+        write_memory(&mut cpu, 0x4000, 0b0000_0100);
+        write_memory(&mut cpu, 0x0000, 0b0100_1010);
+        write_memory(&mut cpu, 0xA000, 0x42);
+        write_memory(&mut cpu, 0x4000, 0x00);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        write_memory(&mut cpu, 0x4000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 0x00);
+        write_memory(&mut cpu, 0x4000, 0x00);
+        assert_eq!(read_memory(&cpu, 0xA000), 0x42);
+    }
+
+    #[test]
+    fn mbc1_large_rom() {
+        let mut cpu = create_banked_cpu(0x03, 0x06, 0x03);
+        write_memory(&mut cpu, 0x2000, 0x00);
+        write_memory(&mut cpu, 0x4000, 0x01);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x21);
+        assert_eq!(read_memory(&cpu, 0x3FFF), 0x00);
+        write_memory(&mut cpu, 0x2000, 0x1F);
+        write_memory(&mut cpu, 0x4000, 0x03);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x7F);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0x3FFF), 0x60);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x7F);
+    }
+
+    #[test]
+    fn mbc1_ram_banking_mode() {
+        let mut cpu = create_banked_cpu(0x03, 0x01, 0x03);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x4000, 0x02);
+        write_memory(&mut cpu, 0xA000, 0x11);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        write_memory(&mut cpu, 0xA000, 0x22);
+        write_memory(&mut cpu, 0x6000, 0x00);
+        assert_eq!(read_memory(&cpu, 0xA000), 0x11);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 0x22);
+        write_memory(&mut cpu, 0x0000, 0x00);
+        assert_eq!(read_memory(&cpu, 0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc1_multicart() {
+        let mut rom = create_banked_rom(0x01, 0x05, 0x00);
+        for game in 0..4 {
+            let start = game * 0x40000 + 0x104;
+            rom[start..start + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+        set_checksums(&mut rom, 0);
+        let mut cpu = create_cartridge_cpu(Cartridge::new(rom).unwrap());
+        write_memory(&mut cpu, 0x4000, 0x02);
+        write_memory(&mut cpu, 0x2000, 0x13);
+        assert_eq!(read_memory(&cpu, 0x7FFF), 0x23);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0x3FFF), 0x20);
+    }
+
+    #[test]
+    fn battery_ram() {
+        let mut cpu = create_banked_cpu(0x03, 0x01, 0x03);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        write_memory(&mut cpu, 0x4000, 0x01);
+        write_memory(&mut cpu, 0xA005, 0x42);
+        let ram = cpu.save_ram().unwrap();
+        assert_eq!(ram.len(), 0x8000);
+        assert_eq!(ram[0x2005], 0x42);
+        let rom = create_banked_rom(0x03, 0x01, 0x03);
+        let mut cpu = create_cartridge_cpu(Cartridge::with_ram(rom, &ram).unwrap());
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        write_memory(&mut cpu, 0x4000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA005), 0x42);
+        assert_eq!(create_banked_cpu(0x02, 0x01, 0x03).save_ram(), None);
+    }
+
+    #[test]
+    fn battery_ram_small() {
+        let mut cpu = create_banked_cpu(0x06, 0x01, 0x00);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0xA010, 0x0B);
+        assert_eq!(cpu.save_ram().unwrap().len(), 0x200);
+        let mut cpu = create_banked_cpu(0x03, 0x01, 0x01);
+        cpu.load_ram(&[0x11; 0x800]);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        assert_eq!(read_memory(&cpu, 0xA000), 0x11);
+        assert_eq!(read_memory(&cpu, 0xB800), 0x11);
+        assert_eq!(cpu.save_ram().unwrap().len(), 0x800);
+    }
+
+    #[test]
+    fn battery_ram_rtc_footer() {
+        let time = Arc::new(Mutex::new(1_000_000));
+        let rom = create_banked_rom(0x10, 0x01, 0x02);
+        let cartridge =
+            Cartridge::with_clock(rom.clone(), Box::new(TestClock(time.clone()))).unwrap();
+        let mut cpu = create_cartridge_cpu(cartridge);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x4000, 0x0A);
+        write_memory(&mut cpu, 0xA000, 5);
+        *time.lock().unwrap() += 30;
+        let save = cpu.save_ram().unwrap();
+        assert_eq!(save.len(), 0x2000 + 48);
+        assert_eq!(save[0x2000 + 8], 5);
+        assert_eq!(save[0x2000 + 28], 5);
+        assert_eq!(save[0x2000 + 40..], (1_000_030u64).to_le_bytes());
+        *time.lock().unwrap() += 3600;
+        let mut cartridge = Cartridge::with_clock(rom, Box::new(TestClock(time.clone()))).unwrap();
+        cartridge.load_ram(&save);
+        let mut cpu = create_cartridge_cpu(cartridge);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x4000, 0x0A);
+        assert_eq!(read_memory(&cpu, 0xA000), 5);
+        write_memory(&mut cpu, 0x6000, 0x00);
+        write_memory(&mut cpu, 0x6000, 0x01);
+        assert_eq!(read_memory(&cpu, 0xA000), 6);
+        write_memory(&mut cpu, 0x4000, 0x08);
+        assert_eq!(read_memory(&cpu, 0xA000), 30);
+    }
+
+    fn scrolling_cpu() -> (Cpu, Rc<RefCell<ScreenFetcher>>) {
+        let rom = vec![
+            0xF0, 0x43, // LDH A, (SCX)
+            0x3C, // INC A
+            0xE0, 0x43, // LDH (SCX), A
+            0x18, 0xF9, // JR -7
+        ];
+        let (mut cpu, lcd_fetcher) = create_cpu_with_screen(rom);
+        write_memory(&mut cpu, 0xFF40, 0b1001_0001);
+        write_memory(&mut cpu, 0xFF47, 0b1110_0100);
+        for row in 0..8 {
+            write_tile_row(&mut cpu, 1, row, 0b1100_1010, 0b1010_0110);
+        }
+        for tile in 0..0x20 {
+            write_memory(&mut cpu, 0x9800 + tile * 3, 1);
+        }
+        (cpu, lcd_fetcher)
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let (mut cpu, lcd_fetcher) = scrolling_cpu();
+        run_frames(3, &mut cpu);
+        let state = cpu.snapshot();
+        run_frames(2, &mut cpu);
+        let frame = lcd_fetcher.borrow().image().to_vec();
+        let registers = cpu.registers.pc();
+
+        cpu.restore(&state).unwrap();
+        assert_eq!(cpu.snapshot(), state);
+        run_frames(2, &mut cpu);
+        assert_eq!(lcd_fetcher.borrow().image().to_vec(), frame);
+        assert_eq!(cpu.registers.pc(), registers);
+
+        let (mut restored, restored_fetcher) = scrolling_cpu();
+        restored.restore(&state).unwrap();
+        run_frames(2, &mut restored);
+        assert_eq!(restored_fetcher.borrow().image().to_vec(), frame);
+    }
+
+    #[test]
+    fn save_state_cartridge() {
+        let mut cpu = create_banked_cpu(0x03, 0x02, 0x03);
+        write_memory(&mut cpu, 0x0000, 0x0A);
+        write_memory(&mut cpu, 0x2000, 0x05);
+        write_memory(&mut cpu, 0xA123, 0x42);
+        let state = cpu.snapshot();
+        write_memory(&mut cpu, 0xA123, 0x00);
+        write_memory(&mut cpu, 0x2000, 0x02);
+        write_memory(&mut cpu, 0x0000, 0x00);
+        cpu.restore(&state).unwrap();
+        assert_eq!(read_memory(&cpu, 0x7FFF), 5);
+        assert_eq!(read_memory(&cpu, 0xA123), 0x42);
+    }
+
+    #[test]
+    fn save_state_boot_rom() {
+        let boot_rom = vec![
+            0x3E, 0x01, // LD A, 1
+            0xE0, 0x50, // LDH (0x50), A
+        ];
+        let rom = add_header(vec![0x00, 0x00, 0x00, 0x00, 0x18, 0xFE]); // JR -2
+        let create = |boot_rom| {
+            let cartridge = Cartridge::new(rom.clone()).unwrap();
+            let lcd_fetcher = Rc::new(RefCell::new(ScreenFetcher::new()));
+            let interrupt = InterruptController::new();
+            Cpu::new(interrupt, cartridge, lcd_fetcher, boot_rom, Model::Dmg)
+        };
+        let mut cpu = create(Some(boot_rom));
+        let state = cpu.snapshot();
+        run_steps_without_wait_cycles(3, &mut cpu);
+        assert_eq!(read_memory(&cpu, 0x0000), 0x00);
+        assert_eq!(cpu.registers.pc(), 4);
+
+        // The boot ROM is mapped again by a state saved before it was turned off
+        cpu.restore(&state).unwrap();
+        assert_eq!(read_memory(&cpu, 0x0000), 0x3E);
+        assert_eq!(cpu.registers.pc(), 0);
+        run_steps_without_wait_cycles(3, &mut cpu);
+        assert_eq!(cpu.registers.pc(), 4);
+
+        let mut other = create(None);
+        assert_eq!(other.restore(&state), Err(StateError::BootRomMissing));
+    }
+
+    #[test]
+    fn save_state_errors() {
+        let (mut cpu, _) = scrolling_cpu();
+        run_frames(1, &mut cpu);
+        let state = cpu.snapshot();
+        let mut other = create_cpu(vec![0x18, 0xFE]);
+        assert_eq!(other.restore(&state), Err(StateError::RomMismatch));
+
+        assert_eq!(
+            cpu.restore(&state[..state.len() - 1]),
+            Err(StateError::InvalidLength)
+        );
+        let mut corrupted = state.clone();
+        corrupted[0] = b'X';
+        assert_eq!(cpu.restore(&corrupted), Err(StateError::InvalidHeader));
+        let mut corrupted = state.clone();
+        corrupted[4] = 0xFF;
+        assert_eq!(
+            cpu.restore(&corrupted),
+            Err(StateError::UnsupportedVersion(0xFF))
+        );
+
+        // A failed restore leaves the machine as it was
+        run_frames(1, &mut cpu);
+        let before = cpu.snapshot();
+        let mut corrupted = state.clone();
+        // The halt bug flag, which is followed by the wait cycles
+        let halt_bug = corrupted.len() - 9;
+        corrupted[halt_bug] = 2;
+        assert_eq!(cpu.restore(&corrupted), Err(StateError::Corrupted));
+        assert_eq!(cpu.snapshot(), before);
+    }
 }
@@ -0,0 +1,291 @@
+use crate::{
+    mem::memory::MapsMemory,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+const DMA_REGISTER: u16 = 0xFF46;
+
+const OAM_START: u16 = 0xFE00;
+const IO_START: u16 = 0xFF00;
+const TRANSFER_LENGTH: u16 = 0xA0;
+
+// One byte is copied per M-cycle, so the whole transfer takes 640 T-cycles
+const TICKS_PER_BYTE: u8 = 4;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Bus {
+    External,
+    Video,
+}
+
+impl Bus {
+    fn of(address: u16) -> Option<Bus> {
+        match address {
+            0x8000..=0x9FFF => Some(Bus::Video),
+            0x0000..=0x7FFF | 0xA000..=0xFDFF => Some(Bus::External),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct OamDma {
+    source: u8,
+    position: Option<u16>,
+    ticks: u8,
+    value: u8,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            source: 0,
+            position: None,
+            ticks: 0,
+            value: 0xFF,
+        }
+    }
+
+    /// Advances the transfer by one T-cycle. Returns the source and OAM address of the byte that
+    /// has to be copied now.
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        let position = self.position?;
+        self.ticks += 1;
+        if self.ticks < TICKS_PER_BYTE {
+            return None;
+        }
+        self.ticks = 0;
+        self.position = Some(position + 1).filter(|&next| next < TRANSFER_LENGTH);
+        Some((self.source_address() + position, OAM_START + position))
+    }
+
+    /// Remembers the byte that is currently on the bus used by the transfer.
+    pub fn transferred(&mut self, value: u8) {
+        self.value = value;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.position.is_some()
+    }
+
+    /// Sources above 0xDFFF read from the echo of work RAM.
+    fn source_address(&self) -> u16 {
+        let source = if self.source >= 0xE0 {
+            self.source - 0x20
+        } else {
+            self.source
+        };
+        u16::from(source) << 8
+    }
+
+    /// Only the I/O registers, HRAM and IE stay available to the CPU while a transfer is
+    /// running, they aren't on the external or the video bus.
+    fn is_blocked(&self, address: u16) -> bool {
+        self.is_active() && address < IO_START
+    }
+
+    /// During a transfer reading from the bus the DMA is using returns the byte that is being
+    /// copied, the rest of the two buses reads 0xFF. Returns `None` if the read isn't affected.
+    pub fn conflicting_read(&self, address: u16) -> Option<u8> {
+        if !self.is_blocked(address) {
+            None
+        } else if Bus::of(address).is_some() && Bus::of(address) == Bus::of(self.source_address()) {
+            Some(self.value)
+        } else {
+            Some(0xFF)
+        }
+    }
+
+    /// Writes below the I/O registers are lost while a transfer is running.
+    pub fn blocks_write(&self, address: u16) -> bool {
+        self.is_blocked(address)
+    }
+}
+
+impl MapsMemory for OamDma {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            DMA_REGISTER => Ok(self.source),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            DMA_REGISTER => {
+                self.source = value;
+                self.position = Some(0);
+                self.ticks = 0;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        address == DMA_REGISTER
+    }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.source);
+        writer.write_bool(self.position.is_some());
+        writer.write_u16(self.position.unwrap_or(0));
+        writer.write_u8(self.ticks);
+        writer.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.source = reader.read_u8()?;
+        let active = reader.read_bool()?;
+        let position = reader.read_u16()?;
+        if position >= TRANSFER_LENGTH {
+            return Err(StateError::Corrupted);
+        }
+        self.position = if active { Some(position) } else { None };
+        self.ticks = reader.read_u8()?;
+        self.value = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+// CGB VRAM DMA
+const HDMA1_REGISTER: u16 = 0xFF51;
+const HDMA2_REGISTER: u16 = 0xFF52;
+const HDMA3_REGISTER: u16 = 0xFF53;
+const HDMA4_REGISTER: u16 = 0xFF54;
+const HDMA5_REGISTER: u16 = 0xFF55;
+
+const VRAM_START: u16 = 0x8000;
+const VRAM_LENGTH: u16 = 0x2000;
+const BLOCK_LENGTH: u16 = 0x10;
+
+// Copying a block takes 8 M-cycles in normal speed and 16 in double speed
+pub const TICKS_PER_BLOCK: i64 = 32;
+
+/// HDMA1-HDMA5, which copy blocks of 0x10 bytes into VRAM. A general purpose transfer copies
+/// everything at once, an HBlank transfer copies one block at the start of every HBlank.
+pub(crate) struct VramDma {
+    enabled: bool,
+    source: u16,
+    destination: u16,
+    // Remaining blocks minus one, as read from HDMA5
+    length: u8,
+    h_blank_active: bool,
+    pending_blocks: u8,
+}
+
+impl VramDma {
+    pub fn new(enabled: bool) -> VramDma {
+        VramDma {
+            enabled,
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            h_blank_active: false,
+            pending_blocks: 0,
+        }
+    }
+
+    /// Called by the PPU when it enters HBlank.
+    pub fn h_blank(&mut self) {
+        if self.h_blank_active {
+            self.pending_blocks = 1;
+        }
+    }
+
+    /// Returns the source and VRAM address of the next block that has to be copied now.
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.pending_blocks == 0 {
+            return None;
+        }
+        self.pending_blocks -= 1;
+        let block = (self.source, VRAM_START + self.destination);
+        self.source = self.source.wrapping_add(BLOCK_LENGTH);
+        self.destination += BLOCK_LENGTH;
+        self.length = self.length.wrapping_sub(1) & 0x7F;
+        // The transfer also ends when the destination runs past the end of VRAM
+        if self.length == 0x7F || self.destination == VRAM_LENGTH {
+            self.finish();
+        }
+        Some(block)
+    }
+
+    fn finish(&mut self) {
+        self.destination %= VRAM_LENGTH;
+        self.length = 0x7F;
+        self.h_blank_active = false;
+        self.pending_blocks = 0;
+    }
+
+    fn write_control(&mut self, value: u8) {
+        if self.h_blank_active && value & 0x80 == 0 {
+            // Cancels the HBlank transfer, HDMA5 keeps the remaining length
+            self.h_blank_active = false;
+            return;
+        }
+        self.length = value & 0x7F;
+        if value & 0x80 == 0 {
+            self.pending_blocks = self.length + 1;
+        } else {
+            self.h_blank_active = true;
+        }
+    }
+}
+
+impl MapsMemory for VramDma {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            HDMA5_REGISTER if self.enabled => Ok(((!self.h_blank_active as u8) << 7) | self.length),
+            HDMA1_REGISTER..=HDMA5_REGISTER => Ok(0xFF),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        if !self.is_in_range(address) {
+            return Err(());
+        }
+        if !self.enabled {
+            return Ok(());
+        }
+        match address {
+            HDMA1_REGISTER => self.source = (self.source & 0x00FF) | (u16::from(value) << 8),
+            HDMA2_REGISTER => self.source = (self.source & 0xFF00) | u16::from(value & 0xF0),
+            HDMA3_REGISTER => {
+                self.destination = (self.destination & 0x00FF) | (u16::from(value & 0x1F) << 8)
+            }
+            HDMA4_REGISTER => {
+                self.destination = (self.destination & 0xFF00) | u16::from(value & 0xF0)
+            }
+            _ => self.write_control(value),
+        }
+        Ok(())
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        (HDMA1_REGISTER..=HDMA5_REGISTER).contains(&address)
+    }
+}
+
+impl SaveState for VramDma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.destination);
+        writer.write_u8(self.length);
+        writer.write_bool(self.h_blank_active);
+        writer.write_u8(self.pending_blocks);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.source = reader.read_u16()? & 0xFFF0;
+        self.destination = reader.read_u16()?;
+        self.length = reader.read_u8()?;
+        self.h_blank_active = reader.read_bool()?;
+        self.pending_blocks = reader.read_u8()?;
+        if self.destination >= VRAM_LENGTH || self.length > 0x7F || self.pending_blocks > 0x80 {
+            return Err(StateError::Corrupted);
+        }
+        Ok(())
+    }
+}
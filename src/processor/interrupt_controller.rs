@@ -1,7 +1,56 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const INTERRUPT_ENABLE_REGISTER: u16 = 0xFFFF;
+pub const INTERRUPT_FLAG_REGISTER: u16 = 0xFF0F;
+
+pub const INTERRUPT_DISPATCH_TICKS: u8 = 20;
+
+const INTERRUPT_MASK: u8 = 0b0001_1111;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    const PRIORITY: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    pub fn bit(self) -> u8 {
+        match self {
+            Interrupt::VBlank => 0,
+            Interrupt::LcdStat => 1,
+            Interrupt::Timer => 2,
+            Interrupt::Serial => 3,
+            Interrupt::Joypad => 4,
+        }
+    }
+
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x0040,
+            Interrupt::LcdStat => 0x0048,
+            Interrupt::Timer => 0x0050,
+            Interrupt::Serial => 0x0058,
+            Interrupt::Joypad => 0x0060,
+        }
+    }
+}
+
 pub(crate) struct InterruptController {
     pub master_enable: bool,
     pub interrupt_enable_flags: u8,
     pub interrupt_request_flags: u8,
+    enable_delay: u8,
 }
 
 impl InterruptController {
@@ -10,6 +59,69 @@ impl InterruptController {
             master_enable: false,
             interrupt_enable_flags: 0,
             interrupt_request_flags: 0,
+            enable_delay: 0,
+        }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.interrupt_request_flags |= 1 << interrupt.bit();
+    }
+
+    pub fn acknowledge(&mut self, interrupt: Interrupt) {
+        self.interrupt_request_flags &= !(1 << interrupt.bit());
+    }
+
+    /// Returns the enabled and requested interrupt with the highest priority, regardless of IME.
+    pub fn pending(&self) -> Option<Interrupt> {
+        let active = self.interrupt_enable_flags & self.interrupt_request_flags & INTERRUPT_MASK;
+        Interrupt::PRIORITY
+            .iter()
+            .find(|interrupt| (active >> interrupt.bit()) & 1 == 1)
+            .copied()
+    }
+
+    /// EI only takes effect after the instruction following it has been executed.
+    pub fn schedule_enable(&mut self) {
+        self.enable_delay = 2;
+    }
+
+    pub fn disable(&mut self) {
+        self.master_enable = false;
+        self.enable_delay = 0;
+    }
+
+    /// Called once after every executed instruction to advance a pending EI.
+    pub fn instruction_executed(&mut self) {
+        if self.enable_delay > 0 {
+            self.enable_delay -= 1;
+            if self.enable_delay == 0 {
+                self.master_enable = true;
+            }
         }
     }
+
+    pub fn read_request_flags(&self) -> u8 {
+        self.interrupt_request_flags | !INTERRUPT_MASK
+    }
+
+    pub fn write_request_flags(&mut self, value: u8) {
+        self.interrupt_request_flags = value & INTERRUPT_MASK;
+    }
+}
+
+impl SaveState for InterruptController {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.master_enable);
+        writer.write_u8(self.interrupt_enable_flags);
+        writer.write_u8(self.interrupt_request_flags);
+        writer.write_u8(self.enable_delay);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.master_enable = reader.read_bool()?;
+        self.interrupt_enable_flags = reader.read_u8()?;
+        self.interrupt_request_flags = reader.read_u8()?;
+        self.enable_delay = reader.read_u8()?;
+        Ok(())
+    }
 }
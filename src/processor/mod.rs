@@ -1,4 +1,8 @@
 pub mod cpu;
+pub mod dma;
 pub mod interrupt_controller;
 pub mod opcodes;
 pub mod registers;
+pub mod serial;
+pub mod speed;
+pub mod timer;
@@ -2256,25 +2256,26 @@ fn nop(opcode: u8, pc: u16, cpu: &mut Cpu) -> u8 {
 /// 01 110 110
 fn halt(opcode: u8, pc: u16, cpu: &mut Cpu) -> u8 {
     debug!("{:#06X}: {:#04X} | HALT", pc, opcode);
-    //unimplemented!();
     cpu.registers.inc_pc(1);
+    cpu.halt();
     4
 }
 
 /// STOP
 /// 00 010 000
 /// 00 000 000
-fn stop(opcode: u8, pc: u16, _: &mut Cpu) -> u8 {
+fn stop(opcode: u8, pc: u16, cpu: &mut Cpu) -> u8 {
     debug!("{:#06X}: {:#04X} | STOP", pc, opcode);
-    unimplemented!();
-    // 4
+    cpu.registers.inc_pc(2);
+    cpu.stop();
+    4
 }
 
 /// EI
 /// 11 111 011
 fn ei(opcode: u8, pc: u16, cpu: &mut Cpu) -> u8 {
     debug!("{:#06X}: {:#04X} | EI", pc, opcode);
-    cpu.interrupt.master_enable = true;
+    cpu.interrupt.schedule_enable();
     cpu.registers.inc_pc(1);
     4
 }
@@ -2283,7 +2284,7 @@ fn ei(opcode: u8, pc: u16, cpu: &mut Cpu) -> u8 {
 /// 11 110 011
 fn di(opcode: u8, pc: u16, cpu: &mut Cpu) -> u8 {
     debug!("{:#06X}: {:#04X} | DI", pc, opcode);
-    cpu.interrupt.master_enable = false;
+    cpu.interrupt.disable();
     cpu.registers.inc_pc(1);
     4
 }
@@ -1,4 +1,7 @@
-use crate::util::bit_op;
+use crate::{
+    state::{SaveState, StateError, StateReader, StateWriter},
+    util::bit_op,
+};
 use std::fmt;
 
 #[derive(Clone)]
@@ -724,6 +727,31 @@ impl Registers {
     }
 }
 
+impl SaveState for Registers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for value in &[
+            self.af(),
+            self.bc(),
+            self.de(),
+            self.hl(),
+            self.sp(),
+            self.pc(),
+        ] {
+            writer.write_u16(*value);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.set_af(reader.read_u16()?);
+        self.set_bc(reader.read_u16()?);
+        self.set_de(reader.read_u16()?);
+        self.set_hl(reader.read_u16()?);
+        self.set_sp(reader.read_u16()?);
+        self.set_pc(reader.read_u16()?);
+        Ok(())
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct FlagCalculations {
     pub zero: FlagCalculationStatus,
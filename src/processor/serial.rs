@@ -0,0 +1,115 @@
+use crate::{
+    mem::memory::MapsMemory,
+    processor::interrupt_controller::{Interrupt, InterruptController},
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+const SERIAL_DATA_REGISTER: u16 = 0xFF01;
+const SERIAL_CONTROL_REGISTER: u16 = 0xFF02;
+
+const TRANSFER_START_BIT: u8 = 0b1000_0000;
+const FAST_CLOCK_BIT: u8 = 0b0000_0010;
+const INTERNAL_CLOCK_BIT: u8 = 0b0000_0001;
+
+// The divider bits whose falling edge shifts one bit at 8192 Hz and, on the CGB, at 262144 Hz
+const CLOCK_DIVIDER_BIT: u16 = 8;
+const FAST_CLOCK_DIVIDER_BIT: u16 = 3;
+
+/// The serial port without a link cable. Transfers on the internal clock shift in 1 bits, as if
+/// no other Game Boy were connected; transfers on an external clock never finish.
+pub(crate) struct Serial {
+    cgb_mode: bool,
+    data: u8,
+    control: u8,
+    remaining_bits: u8,
+    last_clock: bool,
+}
+
+impl Serial {
+    pub fn new(cgb_mode: bool) -> Serial {
+        Serial {
+            cgb_mode,
+            data: 0,
+            control: 0,
+            remaining_bits: 0,
+            last_clock: false,
+        }
+    }
+
+    /// Advances the port by one CPU T-cycle. The clock is derived from the timer's divider, so
+    /// it runs twice as fast in double speed mode.
+    pub fn step(&mut self, divider: u16, interrupt: &mut InterruptController) {
+        let bit = if self.control & FAST_CLOCK_BIT != 0 {
+            FAST_CLOCK_DIVIDER_BIT
+        } else {
+            CLOCK_DIVIDER_BIT
+        };
+        let clock = (divider >> bit) & 1 == 1;
+        let falling_edge = self.last_clock && !clock;
+        self.last_clock = clock;
+        if !falling_edge || self.remaining_bits == 0 || self.control & INTERNAL_CLOCK_BIT == 0 {
+            return;
+        }
+        self.data = (self.data << 1) | 1;
+        self.remaining_bits -= 1;
+        if self.remaining_bits == 0 {
+            self.control &= !TRANSFER_START_BIT;
+            interrupt.request(Interrupt::Serial);
+        }
+    }
+
+    fn control_mask(&self) -> u8 {
+        if self.cgb_mode {
+            TRANSFER_START_BIT | FAST_CLOCK_BIT | INTERNAL_CLOCK_BIT
+        } else {
+            TRANSFER_START_BIT | INTERNAL_CLOCK_BIT
+        }
+    }
+}
+
+impl MapsMemory for Serial {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            SERIAL_DATA_REGISTER => Ok(self.data),
+            SERIAL_CONTROL_REGISTER => Ok(self.control | !self.control_mask()),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            SERIAL_DATA_REGISTER => self.data = value,
+            SERIAL_CONTROL_REGISTER => {
+                self.control = value & self.control_mask();
+                self.remaining_bits = if value & TRANSFER_START_BIT != 0 {
+                    8
+                } else {
+                    0
+                };
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        (SERIAL_DATA_REGISTER..=SERIAL_CONTROL_REGISTER).contains(&address)
+    }
+}
+
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+        writer.write_u8(self.remaining_bits);
+        writer.write_bool(self.last_clock);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.data = reader.read_u8()?;
+        self.control = reader.read_u8()? & self.control_mask();
+        self.remaining_bits = reader.read_u8()?.min(8);
+        self.last_clock = reader.read_bool()?;
+        Ok(())
+    }
+}
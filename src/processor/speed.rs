@@ -0,0 +1,81 @@
+use crate::{
+    mem::memory::MapsMemory,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+const KEY1_REGISTER: u16 = 0xFF4D;
+
+// The CPU is stopped for 2050 M-cycles while the clock settles
+pub const SPEED_SWITCH_TICKS: i64 = 8200;
+
+/// KEY1, which lets CGB games run the CPU, timer and serial port at twice the normal rate.
+pub(crate) struct SpeedSwitch {
+    enabled: bool,
+    double_speed: bool,
+    armed: bool,
+}
+
+impl SpeedSwitch {
+    pub fn new(enabled: bool) -> SpeedSwitch {
+        SpeedSwitch {
+            enabled,
+            double_speed: false,
+            armed: false,
+        }
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Called on STOP. Returns whether the speed changed instead of the CPU being stopped.
+    pub fn switch(&mut self) -> bool {
+        if !self.armed {
+            return false;
+        }
+        self.armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+}
+
+impl MapsMemory for SpeedSwitch {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            KEY1_REGISTER if self.enabled => {
+                Ok(0x7E | ((self.double_speed as u8) << 7) | self.armed as u8)
+            }
+            KEY1_REGISTER => Ok(0xFF),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            KEY1_REGISTER => {
+                if self.enabled {
+                    self.armed = value & 1 == 1;
+                }
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        address == KEY1_REGISTER
+    }
+}
+
+impl SaveState for SpeedSwitch {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.armed);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.double_speed = reader.read_bool()?;
+        self.armed = reader.read_bool()?;
+        Ok(())
+    }
+}
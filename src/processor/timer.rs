@@ -0,0 +1,139 @@
+use crate::{
+    mem::memory::MapsMemory,
+    processor::interrupt_controller::{Interrupt, InterruptController},
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+pub const DIVIDER_REGISTER: u16 = 0xFF04;
+const TIMER_COUNTER_REGISTER: u16 = 0xFF05;
+const TIMER_MODULO_REGISTER: u16 = 0xFF06;
+const TIMER_CONTROL_REGISTER: u16 = 0xFF07;
+
+const TIMER_ENABLE_BIT: u8 = 0b100;
+const TIMA_RELOAD_TICKS: u8 = 4;
+
+pub(crate) struct Timer {
+    divider: u16,
+    counter: u8,
+    modulo: u8,
+    control: u8,
+    reload_delay: u8,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0,
+            counter: 0,
+            modulo: 0,
+            control: 0,
+            reload_delay: 0,
+        }
+    }
+
+    pub fn step(&mut self, interrupt: &mut InterruptController) {
+        if self.reload_delay > 0 {
+            self.reload_delay -= 1;
+            if self.reload_delay == 0 {
+                self.counter = self.modulo;
+                interrupt.request(Interrupt::Timer);
+            }
+        }
+        let divider = self.divider.wrapping_add(1);
+        self.set_divider(divider);
+    }
+
+    /// The internal 16-bit divider. DIV exposes its upper 8 bits.
+    pub fn divider(&self) -> u16 {
+        self.divider
+    }
+
+    fn set_divider(&mut self, divider: u16) {
+        let before = self.timer_signal();
+        self.divider = divider;
+        self.check_falling_edge(before);
+    }
+
+    fn set_control(&mut self, control: u8) {
+        let before = self.timer_signal();
+        self.control = control & 0b111;
+        self.check_falling_edge(before);
+    }
+
+    /// TIMA is clocked by the falling edge of the selected divider bit ANDed with the enable bit,
+    /// which is why resetting DIV or changing TAC can increment TIMA on its own.
+    fn check_falling_edge(&mut self, before: bool) {
+        if before && !self.timer_signal() {
+            self.increment_counter();
+        }
+    }
+
+    fn timer_signal(&self) -> bool {
+        let bit = match self.control & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            0b11 => 7,
+            _ => unreachable!(),
+        };
+        self.control & TIMER_ENABLE_BIT != 0 && (self.divider >> bit) & 1 == 1
+    }
+
+    fn increment_counter(&mut self) {
+        let (counter, overflow) = self.counter.overflowing_add(1);
+        self.counter = counter;
+        if overflow {
+            self.reload_delay = TIMA_RELOAD_TICKS;
+        }
+    }
+}
+
+impl MapsMemory for Timer {
+    fn read(&self, address: u16) -> Result<u8, ()> {
+        match address {
+            DIVIDER_REGISTER => Ok((self.divider >> 8) as u8),
+            TIMER_COUNTER_REGISTER => Ok(self.counter),
+            TIMER_MODULO_REGISTER => Ok(self.modulo),
+            TIMER_CONTROL_REGISTER => Ok(self.control | 0b1111_1000),
+            _ => Err(()),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        match address {
+            DIVIDER_REGISTER => self.set_divider(0),
+            TIMER_COUNTER_REGISTER => {
+                // Writing TIMA while the reload is pending cancels it
+                self.reload_delay = 0;
+                self.counter = value;
+            }
+            TIMER_MODULO_REGISTER => self.modulo = value,
+            TIMER_CONTROL_REGISTER => self.set_control(value),
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn is_in_range(&self, address: u16) -> bool {
+        (DIVIDER_REGISTER..=TIMER_CONTROL_REGISTER).contains(&address)
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.divider);
+        writer.write_u8(self.counter);
+        writer.write_u8(self.modulo);
+        writer.write_u8(self.control);
+        writer.write_u8(self.reload_delay);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.divider = reader.read_u16()?;
+        self.counter = reader.read_u8()?;
+        self.modulo = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        self.reload_delay = reader.read_u8()?;
+        Ok(())
+    }
+}
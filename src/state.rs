@@ -0,0 +1,202 @@
+use std::{convert::TryInto, error::Error, fmt};
+
+const STATE_MAGIC: &[u8; 4] = b"RBST";
+const STATE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    /// The data doesn't start with a save state header
+    InvalidHeader,
+    /// The state was written in a format this version can't read
+    UnsupportedVersion(u16),
+    /// The state was saved while a different ROM was running
+    RomMismatch,
+    /// The state is cut off or followed by unexpected data
+    InvalidLength,
+    /// A value in the state is out of range
+    Corrupted,
+    /// The state was saved while the boot ROM was running, but none is loaded
+    BootRomMissing,
+    /// There is no cartridge loaded to restore the state into
+    NoCartridge,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InvalidHeader => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::RomMismatch => write!(f, "save state belongs to a different ROM"),
+            StateError::InvalidLength => write!(f, "save state has an invalid length"),
+            StateError::Corrupted => write!(f, "save state is corrupted"),
+            StateError::BootRomMissing => write!(f, "save state needs a boot ROM"),
+            StateError::NoCartridge => write!(f, "no cartridge loaded"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// Implemented by every component that is part of a save state. Fields are written in a fixed
+/// order, so once states have been released any change to the layout has to bump
+/// `STATE_VERSION`.
+pub(crate) trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i64(&mut self, value: i64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    /// Writes a length-prefixed block of bytes.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < length {
+            return Err(StateError::InvalidLength);
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, StateError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupted),
+        }
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    /// Reads a block written by `write_bytes` that has to fill `buffer` exactly.
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(StateError::Corrupted);
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// FNV-1a hash of the ROM, stored in the header to refuse states of other games.
+pub(crate) fn rom_checksum(rom: &[u8]) -> u32 {
+    rom.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+pub(crate) fn write_state(state: &dyn SaveState, rom_checksum: u32) -> Vec<u8> {
+    let mut payload = StateWriter::new();
+    state.save_state(&mut payload);
+    let mut writer = StateWriter::new();
+    writer.data.extend_from_slice(STATE_MAGIC);
+    writer.write_u16(STATE_VERSION);
+    writer.write_u32(rom_checksum);
+    writer.write_bytes(&payload.data);
+    writer.data
+}
+
+/// Checks the header and the length of the state before any component is touched.
+pub(crate) fn read_state(
+    state: &mut dyn SaveState,
+    data: &[u8],
+    rom_checksum: u32,
+) -> Result<(), StateError> {
+    if data.len() < STATE_MAGIC.len() || &data[..STATE_MAGIC.len()] != STATE_MAGIC {
+        return Err(StateError::InvalidHeader);
+    }
+    let mut reader = StateReader::new(&data[STATE_MAGIC.len()..]);
+    let version = reader.read_u16()?;
+    if version != STATE_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    if reader.read_u32()? != rom_checksum {
+        return Err(StateError::RomMismatch);
+    }
+    let payload = reader.read_bytes()?;
+    if !reader.is_empty() {
+        return Err(StateError::InvalidLength);
+    }
+    let mut reader = StateReader::new(payload);
+    state.load_state(&mut reader)?;
+    if reader.is_empty() {
+        Ok(())
+    } else {
+        Err(StateError::InvalidLength)
+    }
+}